@@ -1,4 +1,8 @@
-use clap::{command, Parser, Subcommand};
+use std::{env, ffi::OsString, path::PathBuf, time::Duration};
+
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use glob::Pattern;
 
 #[derive(Parser)]
 #[command(author, version, about)]
@@ -8,6 +12,24 @@ pub struct Cli {
     command: Command,
 }
 
+impl Cli {
+    /// Parse the command line of either `cargo gc ...`, which cargo runs as
+    /// `cargo-gc gc ...`, or `cargo-gc ...` invoked directly.
+    pub fn parse_argv() -> Self {
+        Self::parse_from(normalize_argv(env::args_os()))
+    }
+}
+
+/// The command line as cargo passes it, with the `gc` cargo adds after the
+/// program name inserted if it's invoked directly.
+fn normalize_argv(argv: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut argv = argv.into_iter().collect::<Vec<_>>();
+    if argv.get(1).is_none_or(|arg| arg != "gc") {
+        argv.insert(1.min(argv.len()), "gc".into());
+    }
+    argv
+}
+
 #[derive(Subcommand)]
 enum Command {
     Gc(GcCommand),
@@ -16,60 +38,884 @@ enum Command {
 #[derive(Parser)]
 #[command(author, version, about)]
 struct GcCommand {
+    #[command(subcommand)]
+    action: Option<Action>,
+
     /// Display the detailed path of removed files.
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     verbose: bool,
 
+    /// Sort the entries `--verbose` prints by size, the largest first
+    #[arg(long, requires = "verbose")]
+    size_sort: bool,
+
+    /// Print nothing but errors, and the JSON summary with `--format json`
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Don't show the progress spinner
+    #[arg(long, global = true)]
+    no_progress: bool,
+
     /// Perform all checks without making any changes
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     dry_run: bool,
 
-    /// GC artifacts built in release profile
+    /// Estimate outdated files by modification time instead of running `cargo build`
+    #[arg(long, requires = "dry_run")]
+    quick: bool,
+
+    /// Don't remove anything, exit with failure if more than `--max-size` can be reclaimed
+    #[arg(long, requires = "max_size")]
+    check: bool,
+
+    /// Reclaimable size allowed by `--check`, like `500MB` or `2GiB`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "check")]
+    max_size: Option<u64>,
+
+    /// Write the files and directories to remove to a JSON file instead of removing them
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["check", "apply_plan"])]
+    output_plan: Option<PathBuf>,
+
+    /// Remove what's in a plan written by `--output-plan`, except entries changed since
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["check", "prune_removed"])]
+    apply_plan: Option<PathBuf>,
+
+    /// Show the builds of a crate, whether gc would remove them and why cargo rebuilt it
+    #[arg(long, value_name = "CRATE")]
+    explain: Option<String>,
+
+    /// Measure the profile directory before and after removal and compare the
+    /// reclaimed size with the estimate
+    #[arg(long)]
+    measure_delta: bool,
+
+    /// Also write the summary to this file, in `--format`
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Write gauges of the run to this file in the Prometheus text format, for
+    /// the textfile collector of node_exporter
+    #[arg(long, value_name = "FILE")]
+    metrics: Option<PathBuf>,
+
+    /// Also remove crates in cargo's registry and git caches the workspace doesn't use,
+    /// like `cargo gc cache --apply`
+    #[arg(long, conflicts_with_all = ["check", "output_plan", "apply_plan"])]
+    all: bool,
+
+    /// Confirm removing from shared caches with `--all`, or more than `--confirm-over`
     #[arg(short, long)]
+    yes: bool,
+
+    /// Ask before removing more than this from a profile, like `1GB`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    confirm_over: Option<u64>,
+
+    /// Replace identical files under deps by hardlinks after removal
+    #[arg(long)]
+    dedup: bool,
+
+    /// Make read-only files writable when they can't be removed otherwise
+    #[arg(long)]
+    force: bool,
+
+    /// Keep files other running processes have open, like executables of running tests
+    #[arg(long)]
+    skip_in_use: bool,
+
+    /// Warn and go on when a profile or a directory can't be read, instead of
+    /// stopping at the first error
+    #[arg(long)]
+    keep_going: bool,
+
+    /// GC artifacts built in release profile
+    #[arg(short, long, global = true)]
     release: bool,
 
-    /// GC artifacts with the specified profile
+    /// GC artifacts with the specified profile, can be used multiple times
+    #[arg(long, global = true, conflicts_with = "release")]
+    profile: Vec<String>,
+
+    /// Only GC artifacts under the deps directory
+    #[arg(long, conflicts_with = "incremental_only")]
+    deps_only: bool,
+
+    /// Only GC incremental compilation directories, which doesn't need to run `cargo build`
+    #[arg(long, conflicts_with = "no_incremental")]
+    incremental_only: bool,
+
+    /// Don't GC incremental compilation directories
+    #[arg(long)]
+    no_incremental: bool,
+
+    /// GC artifacts of all workspace members, the default
+    #[arg(long, overrides_with = "exclude_member")]
+    workspace: bool,
+
+    /// Never GC artifacts of this workspace member, can be used multiple times
+    #[arg(long, value_name = "NAME", overrides_with = "workspace")]
+    exclude_member: Vec<String>,
+
+    /// Keep outdated executables of binaries and examples, they can still be run
+    #[arg(long)]
+    protect_binaries: bool,
+
+    /// Also remove test, bench and example executables `cargo build` doesn't build,
+    /// instead of keeping the newest of each
+    #[arg(long)]
+    remove_test_binaries: bool,
+
+    /// Keep files and directories matching this glob, relative to the profile
+    /// directory like `deps/*my_plugin*`, can be used multiple times
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    protect: Vec<Pattern>,
+
+    /// Only GC crates of workspace members with files changed since this git ref
+    #[arg(long, value_name = "REF", conflicts_with = "prune_removed")]
+    since: Option<String>,
+
+    /// Only remove artifacts of crates not in Cargo.lock anymore, which doesn't
+    /// need to run `cargo build`
+    #[arg(long, conflicts_with_all = ["deps_only", "incremental_only", "no_incremental"])]
+    prune_removed: bool,
+
+    /// Only remove artifacts built by a rustc other than the one of the latest
+    /// build, which doesn't need to run `cargo build`
+    #[arg(long, conflicts_with_all = ["prune_removed", "since", "incremental_only"])]
+    other_rustc: bool,
+
+    /// Keep the most recently built files of each crate and remove the rest, without
+    /// running `cargo build`, e.g. when it fails
+    #[arg(long, conflicts_with_all = ["quick", "retain_days", "prune_removed", "other_rustc"])]
+    newest_only: bool,
+
+    /// Fall back to `--newest-only` with a warning when `cargo build` fails,
+    /// instead of exiting
+    #[arg(
+        long,
+        conflicts_with_all = ["newest_only", "quick", "retain_days", "prune_removed", "other_rustc"]
+    )]
+    fallback_newest_only: bool,
+
+    /// Keep what was modified within this many days and the newest build of each
+    /// crate, remove everything else without running `cargo build`
+    #[arg(
+        long,
+        value_name = "DAYS",
+        value_parser = parse_days,
+        conflicts_with_all = ["quick", "prune_removed", "other_rustc"]
+    )]
+    retain_days: Option<Duration>,
+
+    /// When building for a target other than the host, also GC the host's
+    /// artifacts in `target/<profile>`, running `cargo build` for both
+    #[arg(long)]
+    include_host: bool,
+
+    /// Number of incremental compilation sessions to keep for each unit of a crate,
+    /// 1 by default or `keep_last` in `[package.metadata.cargo-gc]`
     #[arg(long)]
-    profile: Option<String>,
+    keep_incremental: Option<usize>,
+
+    /// Only remove incremental compilation sessions older than this, like `12h` or `7d`
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    max_age_incremental: Option<Duration>,
+
+    /// Path to Cargo.toml of the project to GC
+    #[arg(long, value_name = "PATH", value_parser = parse_manifest_path, global = true)]
+    manifest_path: Option<PathBuf>,
+
+    /// Require Cargo.lock and cache are up to date, passed to cargo
+    #[arg(long, global = true)]
+    frozen: bool,
+
+    /// Require Cargo.lock is up to date, passed to cargo
+    #[arg(long, global = true)]
+    locked: bool,
+
+    /// Run without accessing the network, passed to cargo
+    #[arg(long, global = true)]
+    offline: bool,
+
+    /// Report file sizes by length instead of the disk space they occupy
+    #[arg(long, global = true)]
+    apparent_size: bool,
+
+    /// Coloring of the output
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = Color::Auto, global = true)]
+    color: Color,
+
+    /// Output format of the result
+    #[arg(long, value_enum, default_value_t = Format::Text, global = true)]
+    format: Format,
 
     /// Arguments pass to `cargo build`, use `--` to separate from `cargo-gc` arguments
     #[arg(trailing_var_arg = true)]
     cargo_args: Vec<String>,
 }
 
+#[derive(Subcommand)]
+enum Action {
+    /// Show how the build artifacts are spending space, without removing anything
+    Stats(StatsCommand),
+    /// Print the files and directories to remove relative to the target directory
+    List(ListCommand),
+    /// Remove documentation of crates that are no longer dependencies from `target/doc`
+    Doc,
+    /// Save the scanned deps directory to JSON, for `stats --import` elsewhere
+    Export(ExportCommand),
+    /// Compare the builds in two profile directories, like `target/debug` of two checkouts
+    Diff(DiffCommand),
+    /// Report inconsistencies in the target directory without removing anything
+    Doctor,
+    /// Print the completion script for a shell
+    Completions(CompletionsCommand),
+    /// Remove crates in cargo's registry and git caches that no project uses
+    Cache(CacheCommand),
+}
+
+#[derive(Parser)]
+struct StatsCommand {
+    /// Show statistics of every profile under the target directory
+    #[arg(long, conflicts_with_all = ["profile", "release", "build"])]
+    all_profiles: bool,
+
+    /// Run `cargo build` to tell outdated artifacts precisely, instead of
+    /// estimating them from modification time
+    #[arg(long)]
+    build: bool,
+
+    /// Report on a snapshot written by `cargo gc export` instead of the target directory
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["all_profiles", "build"])]
+    import: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct ExportCommand {
+    /// Write the snapshot to this file instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Run `cargo build` to record which artifacts are in use
+    #[arg(long)]
+    build: bool,
+}
+
+#[derive(Parser)]
+struct ListCommand {
+    /// Separate paths by NUL instead of newline, for `xargs -0`
+    #[arg(short = '0', long)]
+    null: bool,
+
+    /// Only list this kind of candidates
+    #[arg(long, value_enum)]
+    kind: Option<Kind>,
+}
+
+#[derive(Parser)]
+struct DiffCommand {
+    old: PathBuf,
+    new: PathBuf,
+}
+
+#[derive(Parser)]
+struct CompletionsCommand {
+    /// Shell to generate the completion script for
+    shell: Shell,
+}
+
+#[derive(Parser)]
+struct CacheCommand {
+    /// Path to Cargo.toml of another project whose dependencies to keep, can be
+    /// used multiple times
+    #[arg(long, value_name = "PATH", value_parser = parse_manifest_path)]
+    project: Vec<PathBuf>,
+
+    /// Actually remove the unused crates, which are only reported by default
+    #[arg(long)]
+    apply: bool,
+}
+
+/// Kinds of removal candidates `cargo gc list` can be limited to.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Kind {
+    /// Files in `deps`
+    Deps,
+    /// Directories in `incremental`
+    Incremental,
+    /// Directories in `.fingerprint` and `build`
+    Fingerprint,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Clone)]
+pub enum Mode {
+    Gc,
+    Stats {
+        all_profiles: bool,
+        build: bool,
+        import: Option<PathBuf>,
+    },
+    List {
+        null: bool,
+        kind: Option<Kind>,
+    },
+    Doc,
+    Export {
+        output: Option<PathBuf>,
+        build: bool,
+    },
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+    },
+    Doctor,
+    /// Explain the builds of a crate
+    Explain(String),
+    Completions(Shell),
+    Cache {
+        projects: Vec<PathBuf>,
+        apply: bool,
+    },
+}
+
+#[derive(Clone)]
 pub struct Args {
+    pub mode: Mode,
+    /// The profile to work on, the first of `profiles` unless GC'ing each of them
     pub profile: String,
+    pub profiles: Vec<String>,
+    /// Also GC the host's profile directory when building for another target
+    pub include_host: bool,
+    /// The host's profile directory is GC'ed in this pass, see `include_host`
+    pub host: bool,
     pub verbose: bool,
+    pub quiet: bool,
+    /// List entries to remove by size in verbose output
+    pub size_sort: bool,
+    pub no_progress: bool,
     pub dry_run: bool,
+    /// Estimate outdated files by modification time in dry run
+    pub quick: bool,
+    pub force: bool,
+    pub keep_going: bool,
+    /// Look for files open by other processes before removing
+    pub skip_in_use: bool,
+    pub dedup: bool,
+    /// Clean up cargo's caches after the target directory
+    pub all: bool,
+    /// Removals over this size are confirmed interactively unless `yes`
+    pub confirm_over: Option<u64>,
+    pub yes: bool,
+    pub measure_delta: bool,
+    /// Reclaimable size allowed in check mode
+    pub check: Option<u64>,
+    /// File to write the summary to
+    pub output: Option<PathBuf>,
+    /// Prometheus textfile to write the gauges of the run to
+    pub metrics: Option<PathBuf>,
+    pub output_plan: Option<PathBuf>,
+    pub apply_plan: Option<PathBuf>,
+    /// Whether to GC the deps directory
+    pub gc_deps: bool,
+    /// Whether to GC the incremental directory
+    pub gc_incremental: bool,
+    /// Overrides `keep_last` in package metadata
+    pub keep_incremental: Option<usize>,
+    /// Incremental sessions younger than this are kept
+    pub max_age_incremental: Option<Duration>,
+    /// Keep the newest build of each crate instead of the ones in use by a build
+    pub newest_only: bool,
+    /// Keep the newest build of each crate if `cargo build` fails
+    pub fallback_newest_only: bool,
+    /// Keep deps modified within this besides the newest build of each crate,
+    /// instead of the ones in use by a build
+    pub retain: Option<Duration>,
+    pub protect_binaries: bool,
+    /// Don't keep the newest test executable of each target, see
+    /// [`crate::deps::retain_newest_executables`]
+    pub remove_test_binaries: bool,
+    /// Paths relative to the profile directory never GC'ed
+    pub protect: Vec<Pattern>,
+    /// Workspace members never GC'ed
+    pub exclude_members: Vec<String>,
+    pub since: Option<String>,
+    /// Remove artifacts of crates not in Cargo.lock instead of outdated ones
+    pub prune_removed: bool,
+    /// Remove artifacts of other rustc versions instead of outdated ones
+    pub other_rustc: bool,
+    pub apparent_size: bool,
+    pub manifest_path: Option<PathBuf>,
+    pub frozen: bool,
+    pub locked: bool,
+    pub offline: bool,
+    pub color: Color,
+    pub format: Format,
     pub cargo_args: Vec<String>,
 }
 
 impl Args {
     pub fn from_cli(cli: Cli) -> Self {
         let Command::Gc(cli) = cli.command;
-        let profile = match (cli.profile, cli.release) {
-            (None, true) => "release".into(),
-            (None, false) => "debug".into(),
-            (Some(_), true) => panic!("conflicting usage of --profile and --release"),
-            (Some(profile), false) => profile,
+        if cli.all && !cli.yes && !cli.dry_run {
+            Cli::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--all removes from caches shared by all projects, pass --yes to confirm or --dry-run to preview",
+                )
+                .exit();
+        }
+        // `--profile` conflicts with `--release`
+        let profiles: Vec<String> = match (cli.profile.is_empty(), cli.release) {
+            (true, true) => vec!["release".into()],
+            (true, false) => vec!["dev".into()],
+            (false, _) => cli.profile,
         };
 
+        let mut gc_deps = !cli.incremental_only;
+        // Incremental directories don't tell which rustc wrote them
+        let mut gc_incremental = !cli.deps_only && !cli.no_incremental && !cli.other_rustc;
+        let mode = match cli.action {
+            None => match cli.explain {
+                Some(name) => Mode::Explain(name),
+                None => Mode::Gc,
+            },
+            Some(Action::Stats(stats)) => Mode::Stats {
+                all_profiles: stats.all_profiles,
+                build: stats.build,
+                import: stats.import,
+            },
+            Some(Action::Export(export)) => Mode::Export {
+                output: export.output,
+                build: export.build,
+            },
+            Some(Action::List(list)) => {
+                match list.kind {
+                    Some(Kind::Deps | Kind::Fingerprint) => gc_incremental = false,
+                    Some(Kind::Incremental) => gc_deps = false,
+                    None => {}
+                }
+                Mode::List {
+                    null: list.null,
+                    kind: list.kind,
+                }
+            }
+            Some(Action::Doc) => Mode::Doc,
+            Some(Action::Diff(diff)) => Mode::Diff {
+                old: diff.old,
+                new: diff.new,
+            },
+            Some(Action::Doctor) => Mode::Doctor,
+            Some(Action::Completions(completions)) => Mode::Completions(completions.shell),
+            Some(Action::Cache(cache)) => Mode::Cache {
+                projects: cache.project,
+                apply: cache.apply,
+            },
+        };
         let verbose = cli.verbose;
         let dry_run = cli.dry_run;
+        let retain = cli.retain_days;
 
         Self {
-            profile,
+            mode,
+            profile: profiles[0].clone(),
+            profiles,
+            include_host: cli.include_host,
+            host: false,
             verbose,
+            quiet: cli.quiet,
+            size_sort: cli.size_sort,
+            no_progress: cli.no_progress,
             dry_run,
+            quick: cli.quick,
+            force: cli.force,
+            keep_going: cli.keep_going,
+            skip_in_use: cli.skip_in_use,
+            dedup: cli.dedup,
+            all: cli.all,
+            confirm_over: cli.confirm_over,
+            yes: cli.yes,
+            measure_delta: cli.measure_delta,
+            check: cli.max_size.filter(|_| cli.check),
+            output: cli.output,
+            metrics: cli.metrics,
+            output_plan: cli.output_plan,
+            apply_plan: cli.apply_plan,
+            gc_deps,
+            gc_incremental,
+            keep_incremental: cli.keep_incremental,
+            max_age_incremental: cli.max_age_incremental.or(retain),
+            newest_only: cli.newest_only,
+            fallback_newest_only: cli.fallback_newest_only,
+            retain,
+            protect_binaries: cli.protect_binaries,
+            remove_test_binaries: cli.remove_test_binaries,
+            protect: cli.protect,
+            exclude_members: if cli.workspace {
+                vec![]
+            } else {
+                cli.exclude_member
+            },
+            since: cli.since,
+            prune_removed: cli.prune_removed,
+            other_rustc: cli.other_rustc,
+            apparent_size: cli.apparent_size,
+            manifest_path: cli.manifest_path,
+            frozen: cli.frozen,
+            locked: cli.locked,
+            offline: cli.offline,
+            color: cli.color,
+            format: cli.format,
             cargo_args: cli.cargo_args,
         }
     }
 
     pub fn cargo_profile_args(&self) -> Vec<String> {
-        if self.profile != "debug" {
+        if self.profile != "dev" {
             vec!["--profile".into(), self.profile.clone()]
         } else {
             vec![]
         }
     }
+
+    /// `--frozen`, `--locked` and `--offline` flags for cargo.
+    pub fn cargo_lock_args(&self) -> Vec<String> {
+        [
+            (self.frozen, "--frozen"),
+            (self.locked, "--locked"),
+            (self.offline, "--offline"),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, flag)| flag.to_string())
+        .collect()
+    }
+
+    /// Packages selected by `-p`/`--package` in `cargo_args`, cargo builds
+    /// only them and their dependencies if any.
+    pub fn cargo_packages(&self) -> Vec<String> {
+        let mut packages = vec![];
+        let mut args = self.cargo_args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "-p" || arg == "--package" {
+                packages.extend(args.next().cloned());
+            } else if let Some(package) = arg.strip_prefix("--package=") {
+                packages.push(package.to_string());
+            } else if let Some(package) = arg.strip_prefix("-p").filter(|p| !p.is_empty()) {
+                packages.push(package.to_string());
+            }
+        }
+        packages
+    }
+
+    /// Directory under the target directory holding artifacts of the profile.
+    pub fn profile_dir(&self) -> &str {
+        profile_to_dir(&self.profile)
+    }
+}
+
+/// Cargo puts the `dev` and `test` profiles in the `debug` directory, `bench`
+/// in `release`. Every other profile has a directory of its own name.
+fn profile_to_dir(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
+    }
+}
+
+fn parse_manifest_path(path: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(path);
+    if path.file_name().is_none_or(|name| name != "Cargo.toml") {
+        return Err("the manifest path must be a path to a Cargo.toml file".into());
+    }
+    if !path.is_file() {
+        return Err(format!("manifest path `{}` does not exist", path.display()));
+    }
+    Ok(path)
+}
+
+fn parse_glob(glob: &str) -> Result<Pattern, String> {
+    Pattern::new(glob).map_err(|e| format!("invalid glob `{glob}`: {e}"))
+}
+
+/// Parse a size in bytes, with an optional decimal (`KB`, `MB`, ...) or binary
+/// (`KiB`, `MiB`, ...) unit.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| format!("invalid size `{size}`"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000_u64.pow(2),
+        "g" | "gb" => 1000_u64.pow(3),
+        "t" | "tb" => 1000_u64.pow(4),
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(format!("unknown size unit `{unit}`")),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parse a whole number of days.
+fn parse_days(days: &str) -> Result<Duration, String> {
+    let days = days
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("invalid number of days `{days}`"))?;
+    days.checked_mul(24 * 60 * 60)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("{days} days is too long"))
+}
+
+/// Parse a duration of whole seconds (`s`), minutes (`m`), hours (`h`), days
+/// (`d`) or weeks (`w`). Seconds without a unit.
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(split);
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration `{duration}`"))?;
+    let seconds: u64 = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown duration unit `{unit}`")),
+    };
+    let seconds = number
+        .checked_mul(seconds)
+        .ok_or_else(|| format!("duration `{duration}` is too long"))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(
+            parse_duration("2w"),
+            Ok(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert!(parse_duration("999999999999999w").is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_days() {
+        assert_eq!(parse_days("2"), Ok(Duration::from_secs(2 * 24 * 60 * 60)));
+        assert!(parse_days("999999999999999999").is_err());
+    }
+
+    fn parse(argv: &[&str]) -> Result<Args, clap::Error> {
+        Cli::try_parse_from(argv).map(Args::from_cli)
+    }
+
+    #[test]
+    fn profile_conflicts_with_release() {
+        let error = parse(&["cargo-gc", "gc", "--profile", "bench", "--release"])
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::ArgumentConflict);
+        assert_eq!(error.exit_code(), 2);
+    }
+
+    #[test]
+    fn takes_profiles_one_by_one() {
+        let args = parse(&["cargo-gc", "gc", "--profile", "dev", "--profile", "bench"]).unwrap();
+        assert_eq!(args.profiles, ["dev", "bench"]);
+        assert_eq!(args.profile, "dev");
+        assert_eq!(
+            parse(&["cargo-gc", "gc", "--release"]).unwrap().profiles,
+            ["release"]
+        );
+    }
+
+    #[test]
+    fn selects_the_phases_to_run() {
+        let phases = |flags: &[&str]| {
+            let argv = [&["cargo-gc", "gc"], flags].concat();
+            parse(&argv).map(|args| (args.gc_deps, args.gc_incremental))
+        };
+        assert_eq!(phases(&[]).unwrap(), (true, true));
+        assert_eq!(phases(&["--deps-only"]).unwrap(), (true, false));
+        assert_eq!(phases(&["--no-incremental"]).unwrap(), (true, false));
+        assert_eq!(phases(&["--incremental-only"]).unwrap(), (false, true));
+        assert_eq!(
+            phases(&["--deps-only", "--no-incremental"]).unwrap(),
+            (true, false)
+        );
+        for conflicting in [
+            &["--deps-only", "--incremental-only"][..],
+            &["--incremental-only", "--no-incremental"],
+        ] {
+            assert_eq!(
+                phases(conflicting).unwrap_err().kind(),
+                ErrorKind::ArgumentConflict
+            );
+        }
+    }
+
+    #[test]
+    fn maps_profiles_to_their_directories() {
+        assert_eq!(profile_to_dir("dev"), "debug");
+        assert_eq!(profile_to_dir("test"), "debug");
+        assert_eq!(profile_to_dir("bench"), "release");
+        assert_eq!(profile_to_dir("release"), "release");
+        // Custom profiles have their own directory, whatever they inherit
+        assert_eq!(profile_to_dir("ci"), "ci");
+
+        let args = parse(&["cargo-gc", "gc", "--profile", "ci"]).unwrap();
+        assert_eq!(args.profile_dir(), "ci");
+        assert_eq!(args.cargo_profile_args(), ["--profile", "ci"]);
+    }
+
+    #[test]
+    fn builds_test_and_bench_profiles_into_their_directories() {
+        for (profile, dir) in [("test", "debug"), ("bench", "release")] {
+            let args = parse(&["cargo-gc", "gc", "--profile", profile]).unwrap();
+            assert_eq!(args.profile_dir(), dir);
+            // Built with their own settings, not the ones of `dev` or `release`
+            assert_eq!(args.cargo_profile_args(), ["--profile", profile]);
+        }
+        let args = parse(&["cargo-gc", "gc"]).unwrap();
+        assert_eq!(args.profile_dir(), "debug");
+        assert!(args.cargo_profile_args().is_empty());
+    }
+
+    #[test]
+    fn validates_the_manifest_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = dir.path().join("Cargo.toml");
+        fs::write(&manifest_path, "").unwrap();
+        let manifest_arg = manifest_path.to_str().unwrap();
+
+        let args = parse(&["cargo-gc", "gc", "--manifest-path", manifest_arg]).unwrap();
+        assert_eq!(args.manifest_path, Some(manifest_path.clone()));
+        // Global, so it's taken after a subcommand as well
+        let args = parse(&["cargo-gc", "gc", "stats", "--manifest-path", manifest_arg]).unwrap();
+        assert_eq!(args.manifest_path, Some(manifest_path));
+
+        assert!(parse_manifest_path(dir.path().to_str().unwrap()).is_err());
+        let missing = dir.path().join("missing/Cargo.toml");
+        assert!(parse_manifest_path(missing.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn passes_lock_flags_to_cargo() {
+        let args = parse(&["cargo-gc", "gc"]).unwrap();
+        assert!(args.cargo_lock_args().is_empty());
+        let args = parse(&["cargo-gc", "gc", "--frozen", "--locked", "--offline"]).unwrap();
+        assert_eq!(
+            args.cargo_lock_args(),
+            ["--frozen", "--locked", "--offline"]
+        );
+        let args = parse(&["cargo-gc", "gc", "stats", "--offline"]).unwrap();
+        assert_eq!(args.cargo_lock_args(), ["--offline"]);
+    }
+
+    #[test]
+    fn captures_the_packages_passed_to_cargo() {
+        let args = parse(&["cargo-gc", "gc", "--", "--release"]).unwrap();
+        assert!(args.cargo_packages().is_empty());
+        let args = parse(&[
+            "cargo-gc",
+            "gc",
+            "--",
+            "-p",
+            "first",
+            "--package=second",
+            "-pthird",
+            "--features",
+            "serde",
+            "--package",
+            "fourth",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.cargo_packages(),
+            ["first", "second", "third", "fourth"]
+        );
+    }
+
+    fn normalized(argv: &[&str]) -> Vec<OsString> {
+        normalize_argv(argv.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn normalizes_argv_of_cargo_and_direct_invocation() {
+        let expected = ["cargo-gc", "gc", "--release"].map(OsString::from);
+        assert_eq!(normalized(&["cargo-gc", "gc", "--release"]), expected);
+        assert_eq!(normalized(&["cargo-gc", "--release"]), expected);
+        assert_eq!(
+            normalized(&["cargo-gc"]),
+            ["cargo-gc", "gc"].map(OsString::from)
+        );
+
+        let args = Args::from_cli(Cli::parse_from(normalized(&["cargo-gc", "--release"])));
+        assert_eq!(args.profiles, ["release"]);
+    }
+
+    #[test]
+    fn help_is_of_gc_in_both_invocations() {
+        for argv in [&["cargo-gc", "gc", "--help"][..], &["cargo-gc", "--help"]] {
+            let error = Cli::try_parse_from(normalized(argv)).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::DisplayHelp);
+            assert!(error.to_string().contains("--dry-run"));
+        }
+    }
+
+    #[test]
+    fn generates_completions_of_each_shell() {
+        for shell in Shell::value_variants() {
+            let name = shell.to_string();
+            for argv in [
+                &["cargo-gc", "gc", "completions", &name][..],
+                &["cargo-gc", "completions", &name],
+            ] {
+                let args = Args::from_cli(Cli::parse_from(normalized(argv)));
+                assert!(matches!(args.mode, Mode::Completions(s) if s == *shell));
+            }
+
+            let mut script = vec![];
+            clap_complete::generate(*shell, &mut Cli::command(), "cargo-gc", &mut script);
+            assert!(String::from_utf8(script).unwrap().contains("completions"));
+        }
+    }
 }
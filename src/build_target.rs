@@ -0,0 +1,172 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::cargo_home;
+
+/// The target triple cargo builds for without `--target`, set by
+/// `CARGO_BUILD_TARGET` or `build.target` in cargo's config. Artifacts of it
+/// are under `target/<triple>/<profile>` instead of `target/<profile>`.
+///
+/// `--target` in `cargo_args` takes precedence, like for cargo.
+pub fn build_target(cargo_args: &[String]) -> Result<Option<String>> {
+    if let Some(target) = target_arg(cargo_args) {
+        return Ok(Some(target_dir_name(&target)));
+    }
+    if let Some(target) = env::var_os("CARGO_BUILD_TARGET") {
+        return Ok(Some(target_dir_name(&target.to_string_lossy())));
+    }
+    for config in config_files()? {
+        if let Some(target) = config_build_target(&config)? {
+            return Ok(Some(target_dir_name(&target)));
+        }
+    }
+    Ok(None)
+}
+
+/// Value of the first `--target` passed to cargo.
+fn target_arg(cargo_args: &[String]) -> Option<String> {
+    let mut args = cargo_args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--target" {
+            return args.next().cloned();
+        }
+        if let Some(target) = arg.strip_prefix("--target=") {
+            return Some(target.to_string());
+        }
+    }
+    None
+}
+
+/// `cargo_args` without `--target`, to build for the host.
+pub fn without_target(cargo_args: &[String]) -> Vec<String> {
+    let mut without = vec![];
+    let mut args = cargo_args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--target" {
+            args.next();
+        } else if !arg.starts_with("--target=") {
+            without.push(arg.clone());
+        }
+    }
+    without
+}
+
+/// Config files cargo reads, the ones taking precedence first: those in
+/// `.cargo` of the current directory and its parents, then the one in cargo's
+/// home.
+pub fn config_files() -> Result<Vec<PathBuf>> {
+    let current_dir = env::current_dir().context("failed to get the current directory")?;
+    // Without a home directory there's no config in it
+    let cargo_home = cargo_home().ok();
+    let dirs = current_dir
+        .ancestors()
+        .map(|dir| dir.join(".cargo"))
+        .chain(cargo_home);
+    let mut files = vec![];
+    for dir in dirs {
+        // `config` without extension is the legacy name, used if both exist
+        let file = [dir.join("config"), dir.join("config.toml")]
+            .into_iter()
+            .find(|file| file.is_file());
+        if let Some(file) = file.filter(|file| !files.contains(file)) {
+            files.push(file);
+        }
+    }
+    Ok(files)
+}
+
+/// `build.target` of a config file. If several targets are configured, cargo
+/// builds all of them and the first is taken.
+fn config_build_target(path: &Path) -> Result<Option<String>> {
+    let content = fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    let config: toml::Table =
+        toml::from_str(&content).with_context(|| format!("failed to parse {:?}", path))?;
+    let target = config.get("build").and_then(|build| build.get("target"));
+    Ok(match target {
+        Some(toml::Value::String(target)) => Some(target.clone()),
+        Some(toml::Value::Array(targets)) => targets
+            .first()
+            .and_then(|target| target.as_str())
+            .map(str::to_string),
+        _ => None,
+    })
+}
+
+/// Directory name of a target, custom targets given by a path to their JSON
+/// spec are named after the file.
+fn target_dir_name(target: &str) -> String {
+    if target.ends_with(".json") {
+        if let Some(stem) = Path::new(target).file_stem() {
+            return stem.to_string_lossy().to_string();
+        }
+    }
+    target.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn takes_the_target_passed_to_cargo() {
+        assert_eq!(
+            build_target(&args(&["--target", "wasm32-unknown-unknown", "--locked"])).unwrap(),
+            Some("wasm32-unknown-unknown".to_string())
+        );
+        assert_eq!(
+            build_target(&args(&["--target=wasm32-unknown-unknown"])).unwrap(),
+            Some("wasm32-unknown-unknown".to_string())
+        );
+        assert_eq!(
+            build_target(&args(&["--target", "specs/my-target.json"])).unwrap(),
+            Some("my-target".to_string())
+        );
+    }
+
+    #[test]
+    fn host_pass_builds_without_the_target() {
+        assert_eq!(
+            without_target(&args(&[
+                "--target",
+                "wasm32-unknown-unknown",
+                "-p",
+                "app",
+                "--target=aarch64-apple-darwin",
+                "--locked",
+            ])),
+            args(&["-p", "app", "--locked"])
+        );
+    }
+
+    #[test]
+    fn reads_build_target_of_a_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.toml");
+        fs::write(&config, "[build]\ntarget = \"aarch64-apple-darwin\"\n").unwrap();
+        assert_eq!(
+            config_build_target(&config).unwrap(),
+            Some("aarch64-apple-darwin".to_string())
+        );
+
+        fs::write(
+            &config,
+            "[build]\ntarget = [\"x86_64-pc-windows-msvc\", \"wasm32-wasip1\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config_build_target(&config).unwrap(),
+            Some("x86_64-pc-windows-msvc".to_string())
+        );
+
+        fs::write(&config, "[build]\njobs = 4\n").unwrap();
+        assert_eq!(config_build_target(&config).unwrap(), None);
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, TryLockError},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use cargo_metadata::MetadataCommand;
+use humansize::DECIMAL;
+
+use crate::{args::Args, cargo_home, dir_size, lockfile, metadata_command, SizeCounter};
+
+/// Lock files in `$CARGO_HOME`, cargo holds the first one while downloading
+/// and both of them while removing from its caches.
+const PACKAGE_CACHE_LOCKS: [&str; 2] = [".package-cache", ".package-cache-mutate"];
+
+/// Packages and git revisions referenced by the lockfiles of some projects.
+#[derive(Default)]
+struct LiveSet {
+    /// `{name}-{version}` of registry packages, as named in cargo's cache
+    packages: HashSet<String>,
+    /// Full commit hashes of git dependencies
+    revisions: HashSet<String>,
+}
+
+impl LiveSet {
+    /// Add the packages in the lockfile of `metadata`'s workspace, which
+    /// include optional dependencies `metadata` leaves out.
+    fn add(&mut self, metadata: &cargo_metadata::Metadata) -> Result<()> {
+        for package in lockfile::packages(metadata)? {
+            let Some(source) = &package.source else {
+                continue;
+            };
+            if source.starts_with("git+") {
+                if let Some((_, revision)) = source.rsplit_once('#') {
+                    self.revisions.insert(revision.to_string());
+                }
+            } else {
+                self.packages
+                    .insert(format!("{}-{}", package.name, package.version));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checkouts are named by a prefix of the commit hash.
+    fn has_revision(&self, short: &str) -> bool {
+        self.revisions
+            .iter()
+            .any(|revision| revision.starts_with(short))
+    }
+}
+
+/// Bytes removed from the caches, or reclaimable if nothing is removed.
+#[derive(Default)]
+pub struct CacheReport {
+    /// Registry `.crate` files and their extracted sources
+    pub registry: u64,
+    pub git: u64,
+}
+
+/// Remove crates in cargo's registry and git caches that aren't used by the
+/// current workspace or any of `projects`. Nothing is removed unless `apply`.
+pub fn run(args: &Args, projects: &[PathBuf], apply: bool) -> Result<CacheReport> {
+    let mut live = LiveSet::default();
+    live.add(
+        &metadata_command(args)
+            .exec()
+            .context("failed to retrieve cargo metadata")?,
+    )?;
+    for project in projects {
+        let mut command = MetadataCommand::new();
+        command.other_options(args.cargo_lock_args());
+        live.add(
+            &command
+                .manifest_path(project)
+                .exec()
+                .with_context(|| format!("failed to retrieve cargo metadata of {:?}", project))?,
+        )?;
+    }
+
+    let cargo_home = cargo_home()?;
+    // Held until the removal is done, so no crate is downloaded or extracted
+    // meanwhile
+    let _lock = if apply && !args.dry_run {
+        Some(lock_package_cache(&cargo_home, args.quiet)?)
+    } else {
+        None
+    };
+    let (to_remove, registry_count) = unused_entries(&cargo_home, &live)?;
+
+    let mut size_counter = SizeCounter::new(args.apparent_size);
+    let mut total_size = 0;
+    let sizes = to_remove
+        .iter()
+        .map(|path| {
+            let size = match fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.is_dir() => dir_size(path, &mut size_counter),
+                Ok(metadata) => size_counter.count(&metadata),
+                Err(_) => 0,
+            };
+            total_size += size;
+            size
+        })
+        .collect::<Vec<_>>();
+    if !args.quiet {
+        eprintln!(
+            "found {} unused cache entries in {:?}, {} reclaimable",
+            to_remove.len(),
+            cargo_home,
+            humansize::format_size(total_size, DECIMAL)
+        );
+    }
+    if args.verbose {
+        eprintln!("cache entries to remove {to_remove:#?}");
+    }
+    if !apply || args.dry_run {
+        if !args.quiet {
+            if args.dry_run {
+                eprintln!("abort due to dry run");
+            } else {
+                eprintln!("nothing removed, pass --apply to remove them");
+            }
+        }
+        return Ok(CacheReport {
+            registry: sizes[..registry_count].iter().sum(),
+            git: sizes[registry_count..].iter().sum(),
+        });
+    }
+
+    let mut failed = 0;
+    let mut success_size = 0;
+    let mut report = CacheReport::default();
+    for (index, (path, size)) in to_remove.iter().zip(sizes).enumerate() {
+        let result = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+        if let Err(e) = result {
+            failed += 1;
+            if !args.quiet {
+                eprintln!("failed to remove {:?}: {}", path, e);
+            }
+        } else {
+            success_size += size;
+            if index < registry_count {
+                report.registry += size;
+            } else {
+                report.git += size;
+            }
+        }
+    }
+
+    let fail_report = if failed == 0 {
+        "".to_string()
+    } else {
+        format!(", {} failed to remove", failed)
+    };
+    report!(
+        args,
+        "Removed {} cache entries from {:?}, {} total{}",
+        to_remove.len() - failed,
+        cargo_home,
+        humansize::format_size(success_size, DECIMAL),
+        fail_report,
+    );
+    Ok(report)
+}
+
+/// Cache entries in `cargo_home` not in `live`, registry ones first, and the
+/// number of registry ones.
+fn unused_entries(cargo_home: &Path, live: &LiveSet) -> Result<(Vec<PathBuf>, usize)> {
+    let mut unused = vec![];
+    for cache in registry_dirs(&cargo_home.join("registry").join("cache"))? {
+        for file in entries(&cache)? {
+            let is_live = file
+                .file_name()
+                .and_then(|name| name.to_str()?.strip_suffix(".crate"))
+                .is_none_or(|stem| live.packages.contains(stem));
+            if !is_live {
+                unused.push(file);
+            }
+        }
+    }
+    for src in registry_dirs(&cargo_home.join("registry").join("src"))? {
+        for dir in entries(&src)? {
+            if !live
+                .packages
+                .contains(&*dir.file_name().unwrap_or_default().to_string_lossy())
+            {
+                unused.push(dir);
+            }
+        }
+    }
+    let registry_count = unused.len();
+    for repository in registry_dirs(&cargo_home.join("git").join("checkouts"))? {
+        for checkout in entries(&repository)? {
+            if !live.has_revision(&checkout.file_name().unwrap_or_default().to_string_lossy()) {
+                unused.push(checkout);
+            }
+        }
+    }
+    Ok((unused, registry_count))
+}
+
+/// Acquire the locks cargo takes on its caches before removing from them,
+/// waiting for other cargo processes to finish downloading.
+fn lock_package_cache(cargo_home: &Path, quiet: bool) -> Result<Vec<File>> {
+    fs::create_dir_all(cargo_home)
+        .with_context(|| format!("failed to create directory {:?}", cargo_home))?;
+    PACKAGE_CACHE_LOCKS
+        .iter()
+        .map(|name| {
+            let lock_path = cargo_home.join(name);
+            let lock = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path)
+                .with_context(|| format!("failed to open package cache lock {:?}", lock_path))?;
+            match lock.try_lock() {
+                Ok(()) => return Ok(lock),
+                Err(TryLockError::WouldBlock) if !quiet => {
+                    eprintln!("waiting for the package cache lock {:?}", lock_path);
+                }
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).with_context(|| {
+                        format!("failed to acquire package cache lock {:?}", lock_path)
+                    })
+                }
+            }
+            lock.lock()
+                .with_context(|| format!("failed to acquire package cache lock {:?}", lock_path))?;
+            Ok(lock)
+        })
+        .collect()
+}
+
+/// One sub-directory per registry or git repository, missing if cargo never
+/// fetched from one.
+fn registry_dirs(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![]);
+    }
+    Ok(entries(path)?
+        .into_iter()
+        .filter(|path| path.is_dir())
+        .collect())
+}
+
+fn entries(path: &Path) -> Result<Vec<PathBuf>> {
+    let entries =
+        fs::read_dir(path).with_context(|| format!("failed to read directory: {:?}", path))?;
+    entries
+        .map(|entry| {
+            entry
+                .map(|entry| entry.path())
+                .with_context(|| format!("failed to read entry in {:?}", path))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_entries_no_lockfile_uses() {
+        let cargo_home = tempfile::tempdir().unwrap();
+        let registry = cargo_home.path().join("registry");
+        let cache = registry.join("cache/index.crates.io-6f17d22bba15001f");
+        let src = registry.join("src/index.crates.io-6f17d22bba15001f");
+        let checkouts = cargo_home
+            .path()
+            .join("git/checkouts/repo-0123456789abcdef");
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(cache.join("used-1.0.0.crate"), "").unwrap();
+        fs::write(cache.join("unused-0.1.0.crate"), "").unwrap();
+        fs::create_dir_all(src.join("used-1.0.0")).unwrap();
+        fs::create_dir_all(src.join("unused-0.1.0")).unwrap();
+        fs::create_dir_all(checkouts.join("abc1234")).unwrap();
+        fs::create_dir_all(checkouts.join("def5678")).unwrap();
+        let live = LiveSet {
+            packages: ["used-1.0.0".to_string()].into_iter().collect(),
+            revisions: ["abc1234abc1234abc1234abc1234abc1234abc12".to_string()]
+                .into_iter()
+                .collect(),
+        };
+
+        let (mut unused, registry_count) = unused_entries(cargo_home.path(), &live).unwrap();
+        unused[..registry_count].sort();
+        assert_eq!(
+            unused,
+            [
+                cache.join("unused-0.1.0.crate"),
+                src.join("unused-0.1.0"),
+                checkouts.join("def5678"),
+            ]
+        );
+        assert_eq!(registry_count, 2);
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::{self, File},
+    hash::Hasher,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{file_size, inode};
+
+#[derive(Default)]
+pub struct DedupReport {
+    /// Number of files replaced by hardlinks
+    pub linked: usize,
+    pub saved_size: u64,
+}
+
+/// Replace files with identical content under `path` by hardlinks to one of
+/// them.
+pub fn run(path: &Path, apparent_size: bool) -> Result<DedupReport> {
+    // Only files of the same length can be identical, and only files on the
+    // same filesystem can be hardlinked. A file can be on another one when it's
+    // a mount point itself, linking it would fail with `EXDEV`
+    let mut by_len: HashMap<(Option<u64>, u64), Vec<PathBuf>> = HashMap::new();
+    let entries =
+        fs::read_dir(path).with_context(|| format!("failed to read directory: {:?}", path))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("failed to read entry in {:?}", path))?;
+        let metadata = entry
+            .metadata()
+            .with_context(|| format!("failed to get metadata of {:?}", entry.path()))?;
+        if metadata.is_file() && metadata.len() > 0 {
+            let device = inode(&metadata).map(|(device, _)| device);
+            by_len
+                .entry((device, metadata.len()))
+                .or_default()
+                .push(entry.path());
+        }
+    }
+
+    let mut report = DedupReport::default();
+    for files in by_len.into_values().filter(|files| files.len() > 1) {
+        let mut by_hash: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+        for file in files {
+            let hash = hash(&file).with_context(|| format!("failed to read {:?}", file))?;
+            by_hash.entry(hash).or_default().push(file);
+        }
+
+        for files in by_hash.into_values() {
+            let Some((original, duplicates)) = files.split_first() else {
+                continue;
+            };
+            let original_metadata = fs::metadata(original)
+                .with_context(|| format!("failed to get metadata of {:?}", original))?;
+            for duplicate in duplicates {
+                let metadata = fs::metadata(duplicate)
+                    .with_context(|| format!("failed to get metadata of {:?}", duplicate))?;
+                let already_linked =
+                    inode(&metadata).is_some() && inode(&metadata) == inode(&original_metadata);
+                // Either may be rewritten since grouped by length
+                if already_linked || metadata.len() != original_metadata.len() {
+                    continue;
+                }
+                // Hashes may collide, compare the content to be sure
+                if !same_content(original, duplicate)
+                    .with_context(|| format!("failed to compare {:?}", duplicate))?
+                {
+                    continue;
+                }
+                link(original, duplicate)
+                    .with_context(|| format!("failed to hardlink {:?}", duplicate))?;
+                report.linked += 1;
+                report.saved_size += file_size(&metadata, apparent_size);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Hash of the content of a file, read a buffer at a time.
+fn hash(path: &Path) -> io::Result<u64> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = DefaultHasher::new();
+    loop {
+        let chunk = reader.fill_buf()?;
+        if chunk.is_empty() {
+            return Ok(hasher.finish());
+        }
+        hasher.write(chunk);
+        let len = chunk.len();
+        reader.consume(len);
+    }
+}
+
+/// Whether two files have the same content, compared a buffer at a time.
+fn same_content(left: &Path, right: &Path) -> io::Result<bool> {
+    let mut left = BufReader::new(File::open(left)?);
+    let mut right = BufReader::new(File::open(right)?);
+    loop {
+        let (left_chunk, right_chunk) = (left.fill_buf()?, right.fill_buf()?);
+        let len = left_chunk.len().min(right_chunk.len());
+        if left_chunk[..len] != right_chunk[..len] {
+            return Ok(false);
+        }
+        if len == 0 {
+            return Ok(left_chunk.is_empty() && right_chunk.is_empty());
+        }
+        left.consume(len);
+        right.consume(len);
+    }
+}
+
+/// Replace `duplicate` by a hardlink to `original`. The link is created aside
+/// and renamed over, so `duplicate` is never missing.
+fn link(original: &Path, duplicate: &Path) -> io::Result<()> {
+    let mut temp = duplicate.as_os_str().to_owned();
+    temp.push(".cargo-gc-link");
+    let temp = PathBuf::from(temp);
+    fs::hard_link(original, &temp)?;
+    fs::rename(&temp, duplicate).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_content_across_buffers() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = "x".repeat(20_000);
+        fs::write(dir.path().join("a"), &content).unwrap();
+        fs::write(dir.path().join("b"), &content).unwrap();
+        fs::write(dir.path().join("c"), content.clone() + "y").unwrap();
+        fs::write(dir.path().join("d"), "x".repeat(19_999) + "y").unwrap();
+
+        let path = |name| dir.path().join(name);
+        assert!(same_content(&path("a"), &path("b")).unwrap());
+        assert!(!same_content(&path("a"), &path("c")).unwrap());
+        assert!(!same_content(&path("a"), &path("d")).unwrap());
+        assert_eq!(hash(&path("a")).unwrap(), hash(&path("b")).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_identical_files_and_leaves_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name| dir.path().join(name);
+        fs::write(path("libfoo-0123456789abcdef.rlib"), "same").unwrap();
+        fs::write(path("libfoo-fedcba9876543210.rlib"), "same").unwrap();
+        fs::write(path("libbar-0123456789abcdef.rlib"), "diff").unwrap();
+        fs::write(path("libbaz-0123456789abcdef.rlib"), "other").unwrap();
+
+        let report = run(dir.path(), true).unwrap();
+        assert_eq!(report.linked, 1);
+        assert_eq!(report.saved_size, 4);
+        let inode = |name| inode(&fs::metadata(path(name)).unwrap()).unwrap();
+        assert_eq!(
+            inode("libfoo-0123456789abcdef.rlib"),
+            inode("libfoo-fedcba9876543210.rlib")
+        );
+        assert_ne!(
+            inode("libfoo-0123456789abcdef.rlib"),
+            inode("libbar-0123456789abcdef.rlib")
+        );
+        assert_eq!(
+            fs::read_to_string(path("libbar-0123456789abcdef.rlib")).unwrap(),
+            "diff"
+        );
+    }
+
+    #[test]
+    fn failing_link_leaves_the_duplicate() {
+        let dir = tempfile::tempdir().unwrap();
+        let duplicate = dir.path().join("libfoo-0123456789abcdef.rlib");
+        fs::write(&duplicate, "same").unwrap();
+
+        // Linking fails, like with `EXDEV` across filesystems
+        assert!(link(&dir.path().join("missing"), &duplicate).is_err());
+        assert_eq!(fs::read_to_string(&duplicate).unwrap(), "same");
+
+        // Renaming the link over fails, the link aside is cleaned up
+        let original = dir.path().join("libfoo-fedcba9876543210.rlib");
+        fs::write(&original, "same").unwrap();
+        let directory = dir.path().join("libbar-0123456789abcdef");
+        fs::create_dir(&directory).unwrap();
+        fs::write(directory.join("file"), "").unwrap();
+        assert!(link(&original, &directory).is_err());
+        assert!(directory.join("file").exists());
+        assert!(!dir
+            .path()
+            .join("libbar-0123456789abcdef.cargo-gc-link")
+            .exists());
+    }
+}
@@ -0,0 +1,791 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    artifact_stem, dir_size, extract_figureprint, filesystem::FileSystem, hard_links, inode,
+    warn_unreadable, Figureprints, SizeCounter,
+};
+
+/// A file under the `deps` directory.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Artifact {
+    pub path: PathBuf,
+    pub name: String,
+    pub figureprint: String,
+    pub is_dep_info: bool,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl Artifact {
+    /// Name of the crate. Library files are prefixed by `lib` while their `.d`
+    /// files aren't.
+    pub fn crate_name(&self) -> &str {
+        self.name.strip_prefix("lib").unwrap_or(&self.name)
+    }
+
+    /// Whether the artifact is an executable of one of the `binaries`.
+    pub fn is_executable_of(&self, binaries: &HashSet<String>) -> bool {
+        !self.is_dep_info
+            && self.path.extension().is_none_or(|ext| ext == "exe")
+            && binaries.contains(&self.name)
+    }
+
+    /// Whether the artifact is built from one of the crates. Library files
+    /// have a `lib` prefix that isn't part of the crate name.
+    pub fn is_of(&self, crates: &HashSet<String>) -> bool {
+        crates.contains(&self.name)
+            || self
+                .name
+                .strip_prefix("lib")
+                .is_some_and(|name| crates.contains(name))
+    }
+
+    pub fn is_in_use(&self, figureprints: &Figureprints) -> bool {
+        figureprints.contains(&(self.name.clone(), self.figureprint.clone()))
+    }
+}
+
+/// Artifacts found in a deps directory.
+#[derive(Default)]
+pub struct Scan {
+    pub artifacts: Vec<Artifact>,
+    /// Entries skipped as they can't be read
+    pub unreadable: usize,
+}
+
+/// Scan the deps directory. Reading metadata of every entry dominates on large
+/// target directories, so entries are read by a thread per available core.
+/// Files that aren't build artifacts are skipped, and listed if `verbose`.
+/// Entries that can't be read are skipped with a warning.
+pub fn scan(
+    fs: &dyn FileSystem,
+    deps_path: &Path,
+    apparent_size: bool,
+    verbose: bool,
+) -> Result<Vec<Artifact>> {
+    scan_with_progress(
+        fs,
+        deps_path,
+        apparent_size,
+        verbose,
+        &ProgressBar::hidden(),
+    )
+    .map(|scan| scan.artifacts)
+}
+
+/// [`scan`], advancing `progress` by each entry read and counting the entries
+/// that can't be read.
+pub fn scan_with_progress(
+    fs: &dyn FileSystem,
+    deps_path: &Path,
+    apparent_size: bool,
+    verbose: bool,
+    progress: &ProgressBar,
+) -> Result<Scan> {
+    // Nothing is built into deps yet, e.g. only build scripts ran
+    if !fs.metadata(deps_path).is_ok_and(|metadata| metadata.is_dir) {
+        return Ok(Scan::default());
+    }
+    let files = fs
+        .read_dir(deps_path)
+        .with_context(|| format!("failed to read deps directory: {:?}", deps_path))?;
+    progress.set_length(files.len() as u64);
+
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = files.len().div_ceil(threads).max(1);
+    let chunks = thread::scope(|scope| {
+        files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || scan_files(fs, chunk, apparent_size, verbose, progress))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().expect("scanning thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    progress.finish_and_clear();
+    let mut scan = Scan::default();
+    for chunk in chunks {
+        scan.artifacts.extend(chunk.artifacts);
+        scan.unreadable += chunk.unreadable;
+    }
+    Ok(scan)
+}
+
+fn scan_files(
+    fs: &dyn FileSystem,
+    files: &[PathBuf],
+    apparent_size: bool,
+    verbose: bool,
+    progress: &ProgressBar,
+) -> Scan {
+    let mut scan = Scan::default();
+    for path in files {
+        progress.inc(1);
+        let metadata = match fs.symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                progress.suspend(|| warn_unreadable(path, e));
+                scan.unreadable += 1;
+                continue;
+            }
+        };
+        if metadata.is_dir {
+            continue;
+        }
+
+        let is_dep_info = path.extension().is_some_and(|ext| ext == "d");
+        // Skip files that are not build artifacts, like `.DS_Store`
+        let Some((name, figureprint)) =
+            artifact_stem(path).and_then(|stem| extract_figureprint(&stem))
+        else {
+            if verbose {
+                progress.suspend(|| eprintln!("skipped {:?} (not a build artifact)", path));
+            }
+            continue;
+        };
+
+        // Paths read from deps are already under it. Not resolving symlinks
+        // saves a syscall per file, and removes a link instead of its target
+        scan.artifacts.push(Artifact {
+            path: path.clone(),
+            name,
+            figureprint,
+            is_dep_info,
+            size: metadata.size(apparent_size),
+            modified: metadata.modified,
+        });
+    }
+    scan
+}
+
+/// Artifacts that are not in use. A `.d` file is outdated only when the
+/// artifacts it describes are all removed.
+pub fn outdated(artifacts: Vec<Artifact>, figureprints: &Figureprints) -> Vec<Artifact> {
+    let (dep_infos, artifacts): (Vec<_>, Vec<_>) = artifacts
+        .into_iter()
+        .partition(|artifact| artifact.is_dep_info);
+    let (in_use, mut outdated): (Vec<_>, Vec<_>) = artifacts
+        .into_iter()
+        .partition(|artifact| artifact.is_in_use(figureprints));
+
+    let in_use = in_use
+        .into_iter()
+        .map(|artifact| artifact.figureprint)
+        .collect::<HashSet<_>>();
+    let removed = outdated
+        .iter()
+        .map(|artifact| artifact.figureprint.clone())
+        .collect::<HashSet<_>>();
+    outdated.extend(dep_infos.into_iter().filter(|dep_info| {
+        removed.contains(&dep_info.figureprint) && !in_use.contains(&dep_info.figureprint)
+    }));
+    outdated
+}
+
+/// Estimate the figureprints in use by keeping the most recently built one of
+/// each crate.
+pub fn newest_figureprints(artifacts: &[Artifact]) -> Figureprints {
+    let mut newest: HashMap<&str, (&str, SystemTime)> = HashMap::new();
+    for artifact in artifacts.iter().filter(|artifact| !artifact.is_dep_info) {
+        let entry = newest
+            .entry(&artifact.name)
+            .or_insert((&artifact.figureprint, artifact.modified));
+        if artifact.modified > entry.1 {
+            *entry = (&artifact.figureprint, artifact.modified);
+        }
+    }
+    newest
+        .into_iter()
+        .map(|(name, (figureprint, _))| (name.to_string(), figureprint.to_string()))
+        .collect()
+}
+
+/// Keep the outdated files sharing an inode with a file that's kept, like the
+/// copy in deps of a binary uplifted to the profile directory. Removing them
+/// frees nothing and makes cargo rebuild the kept one. Files of the same
+/// figureprint are kept along with them.
+///
+/// Returns (Outdated file, Kept file) of each pair sharing an inode.
+pub fn retain_unshared(profile_path: &Path, files: &mut Vec<Artifact>) -> Vec<(PathBuf, PathBuf)> {
+    let linked = files
+        .iter()
+        .filter_map(|artifact| {
+            let metadata = fs::metadata(&artifact.path)
+                .ok()
+                .filter(|metadata| hard_links(metadata) > 1)?;
+            Some((inode(&metadata)?, artifact))
+        })
+        .collect::<HashMap<_, _>>();
+    if linked.is_empty() {
+        return vec![];
+    }
+
+    let removed = files
+        .iter()
+        .map(|artifact| artifact.path.as_path())
+        .collect::<HashSet<_>>();
+    let mut shared = vec![];
+    for dir in [
+        profile_path.to_path_buf(),
+        profile_path.join("deps"),
+        profile_path.join("examples"),
+    ] {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if removed.contains(path.as_path()) {
+                continue;
+            }
+            let Some(artifact) = fs::metadata(&path)
+                .ok()
+                .filter(|metadata| metadata.is_file())
+                .and_then(|metadata| inode(&metadata))
+                .and_then(|inode| linked.get(&inode))
+            else {
+                continue;
+            };
+            shared.push((artifact.path.clone(), path));
+        }
+    }
+
+    let kept = shared
+        .iter()
+        .filter_map(|(path, _)| files.iter().find(|artifact| artifact.path == *path))
+        .map(|artifact| artifact.figureprint.clone())
+        .collect::<HashSet<_>>();
+    files.retain(|artifact| !kept.contains(&artifact.figureprint));
+    shared
+}
+
+/// Keep the newest build of each test, bench and example not in
+/// `figureprints`, with the other files of its figureprint. They aren't built by
+/// `cargo build`, the newest is most likely the one `cargo test` or `cargo run
+/// --example` last built.
+///
+/// Their `.fingerprint` directories tell them from other units of the same
+/// name, holding a file named like `test-bin-<name>` or `example-<name>`.
+/// Units whose `.fingerprint` directory can't be read may be any of them, they
+/// are kept with a warning. Returns how many are kept so.
+pub fn retain_newest_executables(
+    profile_path: &Path,
+    files: &mut Vec<Artifact>,
+    figureprints: &Figureprints,
+) -> Result<usize> {
+    let mut kinds = HashMap::new();
+    let mut unreadable = HashSet::new();
+    for (path, _, figureprint) in units(&profile_path.join(".fingerprint"))?.entries {
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn_unreadable(&path, e);
+                unreadable.insert(figureprint);
+                continue;
+            }
+        };
+        let kind = entries
+            .filter_map(|entry| Some(entry.ok()?.file_name().to_string_lossy().to_string()))
+            .find(|name| {
+                (name.starts_with("test-") || name.starts_with("example-"))
+                    && !name.ends_with(".json")
+            });
+        if let Some(kind) = kind {
+            kinds.insert(figureprint, kind);
+        }
+    }
+    let built = figureprints
+        .iter()
+        .filter_map(|(_, figureprint)| kinds.get(figureprint))
+        .collect::<HashSet<_>>();
+
+    // Figureprint and modification time of the newest build of each kind
+    let mut newest: HashMap<&str, (&str, SystemTime)> = HashMap::new();
+    for artifact in files.iter().filter(|artifact| !artifact.is_dep_info) {
+        let Some(kind) = kinds
+            .get(&artifact.figureprint)
+            .filter(|kind| !built.contains(kind))
+        else {
+            continue;
+        };
+        let entry = newest
+            .entry(kind)
+            .or_insert((&artifact.figureprint, artifact.modified));
+        if artifact.modified > entry.1 {
+            *entry = (&artifact.figureprint, artifact.modified);
+        }
+    }
+    let kept = newest
+        .into_values()
+        .map(|(figureprint, _)| figureprint.to_string())
+        .collect::<HashSet<_>>();
+    files.retain(|artifact| {
+        !kept.contains(&artifact.figureprint) && !unreadable.contains(&artifact.figureprint)
+    });
+    Ok(unreadable.len())
+}
+
+/// The newest figureprint of each crate as [`newest_figureprints`], and every
+/// figureprint modified within `retain`.
+pub fn retained_figureprints(artifacts: &[Artifact], retain: Duration) -> Figureprints {
+    let cutoff = SystemTime::now()
+        .checked_sub(retain)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let mut figureprints = newest_figureprints(artifacts);
+    figureprints.extend(
+        artifacts
+            .iter()
+            .filter(|artifact| !artifact.is_dep_info && artifact.modified >= cutoff)
+            .map(|artifact| (artifact.name.clone(), artifact.figureprint.clone())),
+    );
+    figureprints
+}
+
+/// (Path, Size) of the `.fingerprint` directories of the `outdated` artifacts,
+/// and of the `build` directories of build scripts not in `figureprints`
+/// together with their `.fingerprint` directories. Build scripts of packages
+/// that `keep` are never outdated, neither are any without `figureprints`.
+///
+/// Both are named `<package name>-<figureprint>`. A build script has a `build`
+/// directory for compiling it and another for running it, each with its own
+/// figureprint. The second value is the number of entries that can't be read.
+pub fn unit_directories(
+    profile_path: &Path,
+    outdated: &[Artifact],
+    figureprints: Option<&Figureprints>,
+    keep: &dyn Fn(&str) -> bool,
+    size_counter: &mut SizeCounter,
+) -> Result<(Vec<(PathBuf, u64)>, usize)> {
+    let mut outdated_figureprints = outdated
+        .iter()
+        .map(|artifact| artifact.figureprint.clone())
+        .collect::<HashSet<_>>();
+    let mut directories = vec![];
+    let mut unreadable = 0;
+    if let Some(figureprints) = figureprints {
+        let build = units(&profile_path.join("build"))?;
+        unreadable += build.unreadable;
+        for (path, name, figureprint) in build.entries {
+            if keep(&name) || figureprints.contains(&(name, figureprint.clone())) {
+                continue;
+            }
+            let size = dir_size(&path, size_counter);
+            directories.push((path, size));
+            outdated_figureprints.insert(figureprint);
+        }
+    }
+    let fingerprints = units(&profile_path.join(".fingerprint"))?;
+    unreadable += fingerprints.unreadable;
+    for (path, _, figureprint) in fingerprints.entries {
+        if outdated_figureprints.contains(&figureprint) {
+            let size = dir_size(&path, size_counter);
+            directories.push((path, size));
+        }
+    }
+    Ok((directories, unreadable))
+}
+
+/// Unit directories found in `.fingerprint` or `build`.
+#[derive(Default)]
+pub struct Units {
+    /// (Path, Name, Figureprint) of each unit
+    pub entries: Vec<(PathBuf, String, String)>,
+    /// Entries skipped as they can't be read
+    pub unreadable: usize,
+}
+
+/// `<package name>-<figureprint>` directories in `path`, or nothing if it
+/// doesn't exist. Entries that can't be read are skipped with a warning.
+pub fn units(path: &Path) -> Result<Units> {
+    if !path.is_dir() {
+        return Ok(Units::default());
+    }
+    let entries =
+        fs::read_dir(path).with_context(|| format!("failed to read directory: {:?}", path))?;
+    let mut units = Units::default();
+    for entry in entries {
+        let file_type = entry.and_then(|entry| Ok((entry.file_type()?, entry)));
+        let (file_type, entry) = match file_type {
+            Ok(file_type) => file_type,
+            Err(e) => {
+                warn_unreadable(path, e);
+                units.unreadable += 1;
+                continue;
+            }
+        };
+        if !file_type.is_dir() {
+            continue;
+        }
+        if let Some((name, figureprint)) = extract_figureprint(&entry.file_name().to_string_lossy())
+        {
+            units.entries.push((entry.path(), name, figureprint));
+        }
+    }
+    Ok(units)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::OsFileSystem;
+
+    /// Create empty files named `names` in a new deps directory.
+    fn deps_dir(names: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for name in names {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        dir
+    }
+
+    fn scanned_names(deps_path: &Path) -> Vec<(String, String)> {
+        let mut names = scan(&OsFileSystem, deps_path, false, false)
+            .unwrap()
+            .into_iter()
+            .map(|artifact| (artifact.name, artifact.figureprint))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn skips_dot_files() {
+        let dir = deps_dir(&[".DS_Store", "libfoo-0123456789abcdef.rlib"]);
+
+        assert_eq!(
+            scanned_names(dir.path()),
+            [("libfoo".to_string(), "0123456789abcdef".to_string())]
+        );
+    }
+
+    #[test]
+    fn skips_names_without_figureprint() {
+        let dir = deps_dir(&[
+            "libfoo-bar.rlib",
+            "libfoo-0123456789abcdeg.rlib",
+            "libfoo-0123456789abcde.rlib",
+            "-0123456789abcdef",
+            "foo-0123456789abcdef",
+        ]);
+
+        assert_eq!(
+            scanned_names(dir.path()),
+            [("foo".to_string(), "0123456789abcdef".to_string())]
+        );
+    }
+
+    #[test]
+    fn skips_uplifted_artifacts() {
+        // Copies cargo uplifts out of deps have no figureprint
+        let dir = deps_dir(&[
+            "foo",
+            "libfoo.rlib",
+            "foo.d",
+            "libbar-0123456789abcdef.so.1.2",
+        ]);
+
+        assert_eq!(
+            scanned_names(dir.path()),
+            [("libbar".to_string(), "0123456789abcdef".to_string())]
+        );
+    }
+
+    #[test]
+    fn tells_dep_info_apart() {
+        let dir = deps_dir(&["foo-0123456789abcdef", "foo-0123456789abcdef.d"]);
+
+        let mut dep_infos = scan(&OsFileSystem, dir.path(), false, false)
+            .unwrap()
+            .into_iter()
+            .map(|artifact| artifact.is_dep_info)
+            .collect::<Vec<_>>();
+        dep_infos.sort();
+        assert_eq!(dep_infos, [false, true]);
+    }
+
+    #[test]
+    fn removes_stale_builds_end_to_end() {
+        use crate::{filesystem::MemoryFileSystem, remove_file};
+
+        let fs = MemoryFileSystem::default();
+        let built = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        for name in [
+            "libfoo-0123456789abcdef.rlib",
+            "libfoo-0123456789abcdef.rmeta",
+            "foo-0123456789abcdef.d",
+            "libfoo-fedcba9876543210.rlib",
+            "foo-fedcba9876543210.d",
+            "app-1111111111111111",
+            "app-1111111111111111.d",
+            ".DS_Store",
+        ] {
+            fs.add_file(Path::new("/target/debug/deps").join(name), 10, built);
+        }
+        let figureprints = [("libfoo", "0123456789abcdef"), ("app", "1111111111111111")]
+            .into_iter()
+            .map(|(name, figureprint)| (name.to_string(), figureprint.to_string()))
+            .collect();
+
+        let artifacts = scan(&fs, Path::new("/target/debug/deps"), false, false).unwrap();
+        for artifact in outdated(artifacts, &figureprints) {
+            remove_file(&fs, &artifact.path, false).unwrap();
+        }
+
+        let deps = Path::new("/target/debug/deps");
+        let mut remaining = fs
+            .paths()
+            .into_iter()
+            .filter_map(|path| Some(path.strip_prefix(deps).ok()?.to_str()?.to_string()))
+            .filter(|path| !path.is_empty())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            [
+                ".DS_Store",
+                "app-1111111111111111",
+                "app-1111111111111111.d",
+                "foo-0123456789abcdef.d",
+                "libfoo-0123456789abcdef.rlib",
+                "libfoo-0123456789abcdef.rmeta",
+            ]
+        );
+    }
+
+    #[test]
+    fn advances_progress_once_per_entry() {
+        use crate::filesystem::MemoryFileSystem;
+
+        let fs = MemoryFileSystem::default();
+        let deps = Path::new("/target/debug/deps");
+        for name in [
+            "libfoo-0123456789abcdef.rlib",
+            "foo-0123456789abcdef.d",
+            ".DS_Store",
+        ] {
+            fs.add_file(deps.join(name), 10, SystemTime::UNIX_EPOCH);
+        }
+        fs.add_unreadable(deps.join("libbar-0123456789abcdef.rlib"));
+        let files = fs.read_dir(deps).unwrap();
+
+        // Artifacts, other files and unreadable entries alike
+        let progress = ProgressBar::hidden();
+        scan_files(&fs, &files, false, false, &progress);
+        assert_eq!(progress.position(), 4);
+
+        let progress = ProgressBar::hidden();
+        scan_with_progress(&fs, deps, false, false, &progress).unwrap();
+        assert_eq!(progress.length(), Some(4));
+        assert!(progress.is_finished());
+    }
+
+    #[test]
+    fn skips_unreadable_entries_and_goes_on() {
+        use crate::filesystem::MemoryFileSystem;
+
+        let fs = MemoryFileSystem::default();
+        let deps = Path::new("/target/debug/deps");
+        fs.add_file(
+            deps.join("libfoo-0123456789abcdef.rlib"),
+            10,
+            SystemTime::UNIX_EPOCH,
+        );
+        fs.add_file(
+            deps.join("libbar-0123456789abcdef.rlib"),
+            10,
+            SystemTime::UNIX_EPOCH,
+        );
+        fs.add_unreadable(deps.join("libbaz-0123456789abcdef.rlib"));
+
+        let scan = scan_with_progress(&fs, deps, false, false, &ProgressBar::hidden()).unwrap();
+        assert_eq!(scan.unreadable, 1);
+        let mut names = scan
+            .artifacts
+            .into_iter()
+            .map(|artifact| artifact.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["libbar", "libfoo"]);
+    }
+
+    #[test]
+    fn parallel_scan_matches_a_serial_one() {
+        use crate::filesystem::MemoryFileSystem;
+
+        let fs = MemoryFileSystem::default();
+        let deps = Path::new("/target/debug/deps");
+        for i in 0..300u64 {
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(i * 60);
+            let stem = format!("crate{}-{:016x}", i % 40, i);
+            let name = match i % 3 {
+                0 => format!("lib{stem}.rlib"),
+                1 => format!("{stem}.d"),
+                _ => stem,
+            };
+            fs.add_file(deps.join(name), i * 100, modified);
+        }
+        fs.add_file(deps.join(".DS_Store"), 1, SystemTime::UNIX_EPOCH);
+
+        let summary = |scan: Scan| {
+            let mut artifacts = scan
+                .artifacts
+                .into_iter()
+                .map(|artifact| {
+                    (
+                        artifact.path,
+                        artifact.name,
+                        artifact.figureprint,
+                        artifact.is_dep_info,
+                        artifact.size,
+                        artifact.modified,
+                    )
+                })
+                .collect::<Vec<_>>();
+            artifacts.sort();
+            artifacts
+        };
+        let parallel = scan_with_progress(&fs, deps, true, false, &ProgressBar::hidden()).unwrap();
+        let files = fs.read_dir(deps).unwrap();
+        let serial = scan_files(&fs, &files, true, false, &ProgressBar::hidden());
+        let parallel = summary(parallel);
+        assert_eq!(parallel.len(), 300);
+        assert_eq!(parallel, summary(serial));
+    }
+
+    #[test]
+    fn finds_build_script_units_not_in_the_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let profile_path = dir.path();
+        // The compiled build script of `foo` and its run, both in the build,
+        // and a run of an older build
+        for (unit, fingerprint) in [
+            ("foo-0123456789abcdef", "build-script-build-script-build"),
+            (
+                "foo-1111111111111111",
+                "run-build-script-build-script-build",
+            ),
+            (
+                "foo-2222222222222222",
+                "run-build-script-build-script-build",
+            ),
+        ] {
+            fs::create_dir_all(profile_path.join("build").join(unit)).unwrap();
+            let fingerprint_dir = profile_path.join(".fingerprint").join(unit);
+            fs::create_dir_all(&fingerprint_dir).unwrap();
+            fs::write(fingerprint_dir.join(fingerprint), "").unwrap();
+        }
+        let figureprints = [("foo", "0123456789abcdef"), ("foo", "1111111111111111")]
+            .into_iter()
+            .map(|(name, figureprint)| (name.to_string(), figureprint.to_string()))
+            .collect();
+
+        let (directories, unreadable) = unit_directories(
+            profile_path,
+            &[],
+            Some(&figureprints),
+            &|_| false,
+            &mut SizeCounter::new(false),
+        )
+        .unwrap();
+        let mut directories = directories
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        directories.sort();
+        assert_eq!(
+            directories,
+            [
+                profile_path.join(".fingerprint/foo-2222222222222222"),
+                profile_path.join("build/foo-2222222222222222"),
+            ]
+        );
+        assert_eq!(unreadable, 0);
+    }
+
+    #[test]
+    fn keeps_the_newest_executable_of_each_test_not_in_the_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let profile_path = dir.path();
+        let deps = profile_path.join("deps");
+        fs::create_dir(&deps).unwrap();
+        // Two builds of the test `foo`, an older build of the example `ex` whose
+        // newest is in the build, and a library
+        for (unit, fingerprint, modified) in [
+            ("foo-1111111111111111", Some("test-bin-foo"), 1_000),
+            ("foo-2222222222222222", Some("test-bin-foo"), 2_000),
+            ("ex-3333333333333333", Some("example-ex"), 3_000),
+            ("libbar-4444444444444444.rlib", None, 4_000),
+        ] {
+            let path = deps.join(unit);
+            fs::write(&path, "").unwrap();
+            let modified = filetime::FileTime::from_unix_time(modified, 0);
+            filetime::set_file_mtime(&path, modified).unwrap();
+            if let Some(fingerprint) = fingerprint {
+                let fingerprint_dir = profile_path.join(".fingerprint").join(unit);
+                fs::create_dir_all(&fingerprint_dir).unwrap();
+                fs::write(fingerprint_dir.join(fingerprint), "").unwrap();
+                fs::write(fingerprint_dir.join(format!("{fingerprint}.json")), "").unwrap();
+            }
+        }
+        let fingerprint_dir = profile_path.join(".fingerprint/ex-5555555555555555");
+        fs::create_dir_all(&fingerprint_dir).unwrap();
+        fs::write(fingerprint_dir.join("example-ex"), "").unwrap();
+        let figureprints = [("ex".to_string(), "5555555555555555".to_string())]
+            .into_iter()
+            .collect();
+        let mut files = scan(&OsFileSystem, &deps, false, false).unwrap();
+
+        let unreadable =
+            retain_newest_executables(profile_path, &mut files, &figureprints).unwrap();
+        assert_eq!(unreadable, 0);
+        let mut names = files
+            .into_iter()
+            .map(|artifact| (artifact.name, artifact.figureprint))
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                ("ex".to_string(), "3333333333333333".to_string()),
+                ("foo".to_string(), "1111111111111111".to_string()),
+                ("libbar".to_string(), "4444444444444444".to_string()),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_outdated_files_hardlinked_to_kept_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let deps = dir.path().join("deps");
+        fs::create_dir(&deps).unwrap();
+        let linked = deps.join("app-0123456789abcdef");
+        fs::write(&linked, "binary").unwrap();
+        fs::write(deps.join("app-0123456789abcdef.d"), "").unwrap();
+        fs::write(deps.join("libold-fedcba9876543210.rlib"), "").unwrap();
+        // The binary uplifted to the profile directory
+        let uplifted = dir.path().join("app");
+        fs::hard_link(&linked, &uplifted).unwrap();
+        let mut files = scan(&OsFileSystem, &deps, false, false).unwrap();
+
+        let shared = retain_unshared(dir.path(), &mut files);
+        assert_eq!(shared, [(linked, uplifted)]);
+        let names = files
+            .iter()
+            .map(|artifact| artifact.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["libold"]);
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use humansize::DECIMAL;
+use serde::Serialize;
+
+use crate::{
+    args::{Args, Format},
+    deps,
+    filesystem::OsFileSystem,
+};
+
+/// A build of a crate, all files of the same figureprint together.
+#[derive(Serialize)]
+struct Build {
+    name: String,
+    figureprint: String,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct SizeChange {
+    name: String,
+    figureprint: String,
+    old_size: u64,
+    new_size: u64,
+}
+
+#[derive(Serialize)]
+struct ProfileDiff {
+    only_in_old: Vec<Build>,
+    only_in_new: Vec<Build>,
+    size_changed: Vec<SizeChange>,
+}
+
+/// Compare the builds in deps of two profile directories, like
+/// `target/debug` of two checkouts.
+pub fn run(args: &Args, old: &Path, new: &Path) -> Result<()> {
+    let diff = diff(old, new, args.apparent_size)?;
+    match args.format {
+        Format::Text => print_diff(&diff, old, new),
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&diff).context("failed to serialize diff")?
+        ),
+    }
+    Ok(())
+}
+
+fn diff(old: &Path, new: &Path, apparent_size: bool) -> Result<ProfileDiff> {
+    let old_builds = builds(old, apparent_size)?;
+    let mut new_builds = builds(new, apparent_size)?;
+
+    let mut diff = ProfileDiff {
+        only_in_old: vec![],
+        only_in_new: vec![],
+        size_changed: vec![],
+    };
+    for ((name, figureprint), old_size) in old_builds {
+        match new_builds.remove(&(name.clone(), figureprint.clone())) {
+            None => diff.only_in_old.push(Build {
+                name,
+                figureprint,
+                size: old_size,
+            }),
+            Some(new_size) if new_size != old_size => diff.size_changed.push(SizeChange {
+                name,
+                figureprint,
+                old_size,
+                new_size,
+            }),
+            Some(_) => {}
+        }
+    }
+    diff.only_in_new = new_builds
+        .into_iter()
+        .map(|((name, figureprint), size)| Build {
+            name,
+            figureprint,
+            size,
+        })
+        .collect();
+    Ok(diff)
+}
+
+/// Total size of each (name, figureprint) in the deps directory of a profile.
+fn builds(profile_path: &Path, apparent_size: bool) -> Result<BTreeMap<(String, String), u64>> {
+    let deps_path = profile_path.join("deps");
+    if !deps_path.is_dir() {
+        anyhow::bail!(
+            "{:?} is not a profile directory, it has no deps",
+            profile_path
+        );
+    }
+    let mut builds = BTreeMap::new();
+    for artifact in deps::scan(&OsFileSystem, &deps_path, apparent_size, false)? {
+        let name = artifact.crate_name().to_string();
+        *builds.entry((name, artifact.figureprint)).or_default() += artifact.size;
+    }
+    Ok(builds)
+}
+
+fn print_diff(diff: &ProfileDiff, old: &Path, new: &Path) {
+    let print_builds = |builds: &[Build], path: &Path| {
+        if builds.is_empty() {
+            return;
+        }
+        let crates = builds
+            .iter()
+            .map(|build| &build.name)
+            .collect::<BTreeSet<_>>();
+        println!(
+            "only in {:?}: {} builds of {} crates",
+            path,
+            builds.len(),
+            crates.len()
+        );
+        for build in builds {
+            println!(
+                "  {}-{}: {}",
+                build.name,
+                build.figureprint,
+                humansize::format_size(build.size, DECIMAL)
+            );
+        }
+    };
+    print_builds(&diff.only_in_old, old);
+    print_builds(&diff.only_in_new, new);
+    if !diff.size_changed.is_empty() {
+        println!("different in size: {} builds", diff.size_changed.len());
+        for change in &diff.size_changed {
+            println!(
+                "  {}-{}: {} -> {}",
+                change.name,
+                change.figureprint,
+                humansize::format_size(change.old_size, DECIMAL),
+                humansize::format_size(change.new_size, DECIMAL)
+            );
+        }
+    }
+    if diff.only_in_old.is_empty() && diff.only_in_new.is_empty() && diff.size_changed.is_empty() {
+        println!("no difference");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// A profile directory with the `files` of the given sizes in deps.
+    fn profile(files: &[(&str, usize)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("deps")).unwrap();
+        for (name, size) in files {
+            fs::write(dir.path().join("deps").join(name), "x".repeat(*size)).unwrap();
+        }
+        dir
+    }
+
+    fn summary(builds: &[Build]) -> Vec<(&str, &str, u64)> {
+        builds
+            .iter()
+            .map(|build| (build.name.as_str(), build.figureprint.as_str(), build.size))
+            .collect()
+    }
+
+    #[test]
+    fn finds_builds_only_in_one_profile_or_of_another_size() {
+        let old = profile(&[
+            ("libshared-0123456789abcdef.rlib", 10),
+            ("libshared-0123456789abcdef.rmeta", 5),
+            ("libgrown-0123456789abcdef.rlib", 10),
+            ("libgone-0123456789abcdef.rlib", 10),
+        ]);
+        let new = profile(&[
+            ("libshared-0123456789abcdef.rlib", 10),
+            ("libshared-0123456789abcdef.rmeta", 5),
+            ("libgrown-0123456789abcdef.rlib", 20),
+            ("libgone-fedcba9876543210.rlib", 10),
+        ]);
+
+        let diff = diff(old.path(), new.path(), true).unwrap();
+        assert_eq!(
+            summary(&diff.only_in_old),
+            [("gone", "0123456789abcdef", 10)]
+        );
+        assert_eq!(
+            summary(&diff.only_in_new),
+            [("gone", "fedcba9876543210", 10)]
+        );
+        let changes = diff
+            .size_changed
+            .iter()
+            .map(|change| (change.name.as_str(), change.old_size, change.new_size))
+            .collect::<Vec<_>>();
+        assert_eq!(changes, [("grown", 10, 20)]);
+    }
+
+    #[test]
+    fn fails_without_deps() {
+        let old = profile(&[]);
+        let new = tempfile::tempdir().unwrap();
+        assert!(diff(old.path(), new.path(), true).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use humansize::DECIMAL;
+
+use crate::{
+    args::Args, build_target, dir_size, filesystem::OsFileSystem, is_cache_dir_tagged, lockfile,
+    metadata_command, remove_dir, target_directory, SizeCounter,
+};
+
+/// Directories rustdoc shares between crates, they hold one sub-directory per
+/// crate.
+const SHARED_DIRS: [&str; 2] = ["src", "search.desc"];
+
+/// Directories rustdoc generates that don't belong to a single crate. Other
+/// ones like `static.files` have a dot in the name, which crate names can't.
+const RESERVED_DIRS: [&str; 2] = ["src", "implementors"];
+
+pub fn run(args: &Args) -> Result<()> {
+    let metadata = metadata_command(args)
+        .exec()
+        .context("failed to retrieve cargo metadata")?;
+    let live_crates = lockfile::live_crates(&metadata)?;
+    let target_directory = target_directory(&OsFileSystem, &metadata)?;
+    // Like profiles, documentation for another target is in `target/<triple>`
+    let doc_path = match build_target::build_target(&args.cargo_args)? {
+        Some(target) if !args.host => target_directory.join(target),
+        _ => target_directory,
+    }
+    .join("doc");
+    if !doc_path.is_dir() {
+        report!(args, "no documentation found in {:?}", doc_path);
+        return Ok(());
+    }
+
+    let dirs_to_remove = outdated_directories(&doc_path, &live_crates)?;
+    if !args.quiet {
+        eprintln!("found {} outdated doc directories", dirs_to_remove.len());
+    }
+    if args.verbose {
+        eprintln!("doc directories to remove {dirs_to_remove:#?}");
+    }
+    if args.dry_run {
+        if !args.quiet {
+            eprintln!("abort due to dry run");
+        }
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    let mut success_size = 0;
+    let total_count = dirs_to_remove.len();
+    let mut size_counter = SizeCounter::new(args.apparent_size);
+    for dir in dirs_to_remove {
+        let size = dir_size(&dir, &mut size_counter);
+        if let Err(e) = remove_dir(&OsFileSystem, &dir, args.force) {
+            failed += 1;
+            eprintln!("failed to remove directory: {}", e);
+        } else {
+            success_size += size;
+        }
+    }
+
+    let fail_report = if failed == 0 {
+        "".to_string()
+    } else {
+        format!(", {} failed to remove", failed)
+    };
+    report!(
+        args,
+        "Removed {} doc directories from {:?}, {} total{}",
+        total_count - failed,
+        doc_path,
+        humansize::format_size(success_size, DECIMAL),
+        fail_report,
+    );
+    Ok(())
+}
+
+/// Documentation directories of crates that are no longer in the dependency
+/// graph. Assets shared by all crates like `search-index.js` are kept.
+fn outdated_directories(doc_path: &Path, live_crates: &HashSet<String>) -> Result<Vec<PathBuf>> {
+    let mut outdated = vec![];
+    for crate_dir in crate_directories(doc_path)? {
+        if !is_live(&crate_dir, live_crates) {
+            outdated.push(crate_dir);
+        }
+    }
+    for shared_dir in SHARED_DIRS {
+        let shared_path = doc_path.join(shared_dir);
+        if !shared_path.is_dir() {
+            continue;
+        }
+        for crate_dir in crate_directories(&shared_path)? {
+            if !is_live(&crate_dir, live_crates) {
+                outdated.push(crate_dir);
+            }
+        }
+    }
+    Ok(outdated)
+}
+
+/// Sub-directories of `path`, except the ones generated by rustdoc itself.
+fn crate_directories(path: &Path) -> Result<Vec<PathBuf>> {
+    let entries =
+        fs::read_dir(path).with_context(|| format!("failed to read directory: {:?}", path))?;
+    let mut dirs = vec![];
+    for entry in entries {
+        let entry = entry.with_context(|| format!("failed to read entry in {:?}", path))?;
+        let is_dir = entry
+            .file_type()
+            .with_context(|| format!("failed to get fs entry type of {:?}", entry.path()))?
+            .is_dir();
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if is_dir
+            && !file_name.contains('.')
+            && !RESERVED_DIRS.contains(&file_name.as_str())
+            && !is_cache_dir_tagged(&entry.path())
+        {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+fn is_live(crate_dir: &Path, live_crates: &HashSet<String>) -> bool {
+    let name = crate_dir
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    live_crates.contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_directories_of_crates_no_longer_in_the_graph() {
+        let dir = tempfile::tempdir().unwrap();
+        let doc_path = dir.path();
+        for path in [
+            "app",
+            "gone",
+            "src/app",
+            "src/gone",
+            "search.desc/app",
+            "search.desc/gone",
+            "implementors/gone",
+            "static.files",
+        ] {
+            fs::create_dir_all(doc_path.join(path)).unwrap();
+        }
+        fs::write(doc_path.join("search-index.js"), "").unwrap();
+
+        let mut outdated =
+            outdated_directories(doc_path, &HashSet::from(["app".to_string()])).unwrap();
+        outdated.sort();
+        assert_eq!(
+            outdated,
+            ["gone", "search.desc/gone", "src/gone"].map(|path| doc_path.join(path))
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{args::Args, artifact_stem, extract_figureprint, incremental, Workspace};
+
+/// How many example paths are shown for each problem, unless verbose.
+const PROBLEM_EXAMPLES: usize = 3;
+
+/// A kind of inconsistency in the profile directory and the paths showing it.
+struct Problem {
+    description: &'static str,
+    hint: &'static str,
+    paths: Vec<PathBuf>,
+}
+
+/// Report entries of the profile directory gc can't make sense of, without
+/// removing anything.
+pub fn run(args: &Args, workspace: &Workspace) -> Result<()> {
+    let profile_path = workspace.profile_path(args);
+    if !profile_path.is_dir() {
+        println!("no build artifacts found in {:?}", profile_path);
+        return Ok(());
+    }
+
+    let mut found = false;
+    for problem in problems(&profile_path)?
+        .iter()
+        .filter(|problem| !problem.paths.is_empty())
+    {
+        found = true;
+        println!("{} {}", problem.paths.len(), problem.description);
+        let examples = if args.verbose {
+            problem.paths.len()
+        } else {
+            PROBLEM_EXAMPLES
+        };
+        for path in problem.paths.iter().take(examples) {
+            println!("  {:?}", path);
+        }
+        if problem.paths.len() > examples {
+            println!("  ... and {} more", problem.paths.len() - examples);
+        }
+        println!("  hint: {}", problem.hint);
+    }
+    if !found {
+        println!("no problems found in {:?}", profile_path);
+    }
+    Ok(())
+}
+
+/// Each kind of problem gc checks for, with the paths showing it if any.
+fn problems(profile_path: &Path) -> Result<[Problem; 4]> {
+    let deps = entries(&profile_path.join("deps"))?;
+    let fingerprints = entries(&profile_path.join(".fingerprint"))?;
+    let builds = entries(&profile_path.join("build"))?;
+    let incremental = entries(&profile_path.join("incremental"))?;
+
+    let mut unparsable = vec![];
+    let mut deps_figureprints = HashSet::new();
+    let mut deps_with_figureprint = vec![];
+    for path in deps {
+        match artifact_stem(&path)
+            .as_deref()
+            .and_then(extract_figureprint)
+        {
+            Some((_, figureprint)) => {
+                deps_figureprints.insert(figureprint.clone());
+                deps_with_figureprint.push((path, figureprint));
+            }
+            None => unparsable.push(path),
+        }
+    }
+
+    let fingerprint_figureprints = fingerprints
+        .iter()
+        .filter_map(|path| Some(extract_figureprint(&file_name(path))?.1))
+        .collect::<HashSet<_>>();
+    let build_figureprints = builds
+        .iter()
+        .filter_map(|path| Some(extract_figureprint(&file_name(path))?.1))
+        .collect::<HashSet<_>>();
+
+    Ok([
+        Problem {
+            description: "files in deps without a matching .fingerprint directory",
+            hint: "cargo will rebuild them, they are safe to remove",
+            paths: deps_with_figureprint
+                .into_iter()
+                .filter(|(_, figureprint)| !fingerprint_figureprints.contains(figureprint))
+                .map(|(path, _)| path)
+                .collect(),
+        },
+        Problem {
+            description: ".fingerprint directories without files in deps or build",
+            hint: "left by removed artifacts, `cargo check` or `cargo doc`, they are harmless",
+            paths: fingerprints
+                .into_iter()
+                .filter(|path| {
+                    extract_figureprint(&file_name(path)).is_none_or(|(_, figureprint)| {
+                        !deps_figureprints.contains(&figureprint)
+                            && !build_figureprints.contains(&figureprint)
+                    })
+                })
+                .collect(),
+        },
+        Problem {
+            description: "files in deps without a figureprint in the name",
+            hint: "gc never removes them, check whether something else writes into deps",
+            paths: unparsable,
+        },
+        Problem {
+            description: "incremental directories not named `<crate>-<hash>`",
+            hint: "gc never removes them, they may be left by another rustc version",
+            paths: incremental
+                .into_iter()
+                .filter(|path| incremental::crate_name(&file_name(path)).is_none())
+                .collect(),
+        },
+    ])
+}
+
+/// Entries of `path`, or nothing if it doesn't exist.
+fn entries(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![]);
+    }
+    let entries =
+        fs::read_dir(path).with_context(|| format!("failed to read directory: {:?}", path))?;
+    entries
+        .map(|entry| {
+            entry
+                .map(|entry| entry.path())
+                .with_context(|| format!("failed to read entry in {:?}", path))
+        })
+        .collect()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(profile_path: &Path, dirs: &[&str], files: &[&str]) {
+        for dir in dirs {
+            fs::create_dir_all(profile_path.join(dir)).unwrap();
+        }
+        for file in files {
+            fs::write(profile_path.join(file), "").unwrap();
+        }
+    }
+
+    #[test]
+    fn finds_no_problem_in_a_healthy_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        create(
+            dir.path(),
+            &[
+                "deps",
+                ".fingerprint/app-0123456789abcdef",
+                ".fingerprint/build-script-fedcba9876543210",
+                "build/build-script-fedcba9876543210",
+                "incremental/app-1m2n3o4p5q6r7",
+            ],
+            &[
+                "deps/libapp-0123456789abcdef.rlib",
+                "deps/app-0123456789abcdef.d",
+            ],
+        );
+
+        let problems = problems(dir.path()).unwrap();
+        assert!(problems.iter().all(|problem| problem.paths.is_empty()));
+    }
+
+    #[test]
+    fn finds_each_problem_of_a_broken_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        create(
+            dir.path(),
+            &[
+                "deps",
+                ".fingerprint/app-0123456789abcdef",
+                ".fingerprint/gone-fedcba9876543210",
+                "incremental/app-1m2n3o4p5q6r7",
+                "incremental/app-",
+                "incremental/odd",
+            ],
+            &[
+                "deps/libapp-0123456789abcdef.rlib",
+                "deps/liborphan-1111111111111111.rlib",
+                "deps/notes.txt",
+            ],
+        );
+
+        let [orphans, unused_fingerprints, unparsable, incremental] =
+            problems(dir.path()).unwrap().map(|problem| {
+                let mut paths = problem.paths;
+                paths.sort();
+                paths
+            });
+        let paths = |paths: &[&str]| {
+            paths
+                .iter()
+                .map(|path| dir.path().join(path))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(orphans, paths(&["deps/liborphan-1111111111111111.rlib"]));
+        assert_eq!(
+            unused_fingerprints,
+            paths(&[".fingerprint/gone-fedcba9876543210"])
+        );
+        assert_eq!(unparsable, paths(&["deps/notes.txt"]));
+        assert_eq!(incremental, paths(&["incremental/app-", "incremental/odd"]));
+    }
+}
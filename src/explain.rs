@@ -0,0 +1,322 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+use humansize::DECIMAL;
+use serde::Serialize;
+
+use crate::{
+    args::{Args, Format},
+    build,
+    deps::{self, Artifact},
+    filesystem::OsFileSystem,
+    normalize_crate_name, Figureprints, Workspace,
+};
+
+/// Cargo logs why a unit is rebuilt under this target.
+const FINGERPRINT_LOG: &str = "cargo::core::compiler::fingerprint";
+
+/// Builds of a crate and why cargo rebuilt it, printed in `--format`.
+#[derive(Serialize)]
+struct Explanation {
+    name: String,
+    profile_path: PathBuf,
+    builds: Vec<Build>,
+    /// Messages cargo logged about the fingerprints of the crate's units,
+    /// empty if it's fresh
+    dirty_reasons: Vec<String>,
+}
+
+/// Files of one figureprint in deps.
+#[derive(Serialize)]
+struct Build {
+    figureprint: String,
+    size: u64,
+    status: Status,
+    /// `.fingerprint` directories of the build
+    fingerprints: Vec<PathBuf>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    InUse,
+    NeverGc,
+    NotChanged,
+    Outdated,
+}
+
+impl Status {
+    fn describe(&self) -> &'static str {
+        match self {
+            Status::InUse => "in use",
+            Status::NeverGc => "kept by never_gc in package metadata or --exclude-member",
+            Status::NotChanged => "kept as it's not changed since --since",
+            Status::Outdated => "outdated, not built by the current `cargo build`",
+        }
+    }
+}
+
+/// Show every build of a crate in deps and whether gc would remove it, then
+/// why cargo rebuilt the crate if it did.
+pub fn run(args: &Args, workspace: &Workspace, name: &str) -> Result<()> {
+    let name = normalize_crate_name(name);
+    let crates = HashSet::from([name.clone()]);
+    let log = format!("{FINGERPRINT_LOG}=info");
+    let (collection, stderr) = build(args, workspace, &[("CARGO_LOG", &log)])?;
+    let figureprints = collection.deps_figureprints;
+
+    let profile_path = workspace.profile_path(args);
+    let artifacts = deps::scan(
+        &OsFileSystem,
+        &profile_path.join("deps"),
+        args.apparent_size,
+        args.verbose,
+    )?
+    .into_iter()
+    .filter(|artifact| artifact.is_of(&crates))
+    .collect::<Vec<_>>();
+
+    let fingerprints = match fs::read_dir(profile_path.join(".fingerprint")) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .collect(),
+        Err(_) => vec![],
+    };
+
+    let explanation = Explanation {
+        builds: builds(&name, &artifacts, &figureprints, &fingerprints, workspace),
+        dirty_reasons: dirty_reasons(&stderr, &name)
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+        name,
+        profile_path,
+    };
+    match args.format {
+        Format::Text => print(&explanation),
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&explanation)
+                .context("failed to serialize the explanation")?
+        ),
+    }
+    Ok(())
+}
+
+/// Each figureprint of the crate's `artifacts` with whether gc would remove
+/// it, and its `.fingerprint` directories out of `fingerprints`.
+fn builds(
+    name: &str,
+    artifacts: &[Artifact],
+    figureprints: &Figureprints,
+    fingerprints: &[PathBuf],
+    workspace: &Workspace,
+) -> Vec<Build> {
+    // (Whether in use, total size) of each figureprint
+    let mut sizes: BTreeMap<&str, (bool, u64)> = BTreeMap::new();
+    for artifact in artifacts {
+        let entry = sizes.entry(&artifact.figureprint).or_default();
+        entry.0 |= !artifact.is_dep_info && artifact.is_in_use(figureprints);
+        entry.1 += artifact.size;
+    }
+    sizes
+        .into_iter()
+        .map(|(figureprint, (in_use, size))| {
+            let status = if in_use {
+                Status::InUse
+            } else if workspace.policy.never_gc.contains(name) {
+                Status::NeverGc
+            } else if workspace
+                .changed_crates
+                .as_ref()
+                .is_some_and(|changed| !changed.contains(name))
+            {
+                Status::NotChanged
+            } else {
+                Status::Outdated
+            };
+            // Named by the package, which may differ from the crate
+            let suffix = format!("-{figureprint}");
+            let fingerprints = fingerprints
+                .iter()
+                .filter(|path| {
+                    path.file_name()
+                        .is_some_and(|file_name| file_name.to_string_lossy().ends_with(&suffix))
+                })
+                .cloned()
+                .collect();
+            Build {
+                figureprint: figureprint.to_string(),
+                size,
+                status,
+                fingerprints,
+            }
+        })
+        .collect()
+}
+
+fn print(explanation: &Explanation) {
+    let Explanation {
+        name,
+        profile_path,
+        builds,
+        dirty_reasons,
+    } = explanation;
+    if builds.is_empty() {
+        println!(
+            "no build of {} found in {:?}",
+            name,
+            profile_path.join("deps")
+        );
+    } else {
+        println!("{} builds of {} in {:?}", builds.len(), name, profile_path);
+    }
+    for build in builds {
+        println!(
+            "  {}  {}  {}",
+            build.figureprint,
+            humansize::format_size(build.size, DECIMAL),
+            build.status.describe()
+        );
+        for fingerprint in &build.fingerprints {
+            println!("    fingerprint {:?}", fingerprint);
+        }
+    }
+
+    if dirty_reasons.is_empty() {
+        println!("{} is fresh, cargo didn't rebuild it", name);
+    } else {
+        println!("cargo rebuilt {}:", name);
+        for reason in dirty_reasons {
+            println!("  {}", reason);
+        }
+    }
+}
+
+/// Messages cargo logged about the fingerprints of the crate's units.
+fn dirty_reasons<'a>(stderr: &'a str, name: &str) -> Vec<&'a str> {
+    stderr
+        .lines()
+        .filter(|line| {
+            line.split_once("target=\"")
+                .and_then(|(_, rest)| rest.split_once('"'))
+                .is_some_and(|(target, _)| normalize_crate_name(target) == name)
+        })
+        .filter_map(|line| Some(line.split_once(&format!("{FINGERPRINT_LOG}: "))?.1.trim()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::Policy;
+
+    fn workspace(never_gc: &[&str]) -> Workspace {
+        let mut policy = Policy::default();
+        policy.never_gc = never_gc.iter().map(|name| name.to_string()).collect();
+        Workspace {
+            manifest_path: PathBuf::from("Cargo.toml"),
+            target_directory: PathBuf::from("target"),
+            build_target: None,
+            policy,
+            changed_crates: None,
+        }
+    }
+
+    #[test]
+    fn tells_why_each_build_is_kept_or_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in [
+            "libfoo-0123456789abcdef.rlib",
+            "foo-0123456789abcdef.d",
+            "libfoo-fedcba9876543210.rlib",
+        ] {
+            fs::write(dir.path().join(file), "x").unwrap();
+        }
+        let artifacts = deps::scan(&OsFileSystem, dir.path(), true, false).unwrap();
+        let figureprints =
+            Figureprints::from([("libfoo".to_string(), "0123456789abcdef".to_string())]);
+        let fingerprints = [PathBuf::from(".fingerprint/foo-fedcba9876543210")];
+
+        let explained = builds(
+            "foo",
+            &artifacts,
+            &figureprints,
+            &fingerprints,
+            &workspace(&[]),
+        );
+        let statuses = explained
+            .iter()
+            .map(|build| (build.figureprint.as_str(), build.status.describe()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                ("0123456789abcdef", "in use"),
+                (
+                    "fedcba9876543210",
+                    "outdated, not built by the current `cargo build`"
+                ),
+            ]
+        );
+        assert_eq!(explained[0].size, 2);
+        assert_eq!(explained[1].fingerprints, fingerprints);
+
+        let never_gc = builds(
+            "foo",
+            &artifacts,
+            &figureprints,
+            &fingerprints,
+            &workspace(&["foo"]),
+        );
+        assert!(matches!(never_gc[1].status, Status::NeverGc));
+    }
+
+    #[test]
+    fn serializes_every_build_for_tools() {
+        let explanation = Explanation {
+            name: "foo".to_string(),
+            profile_path: PathBuf::from("target/debug"),
+            builds: ["0123456789abcdef", "fedcba9876543210"]
+                .map(|figureprint| Build {
+                    figureprint: figureprint.to_string(),
+                    size: 1,
+                    status: Status::Outdated,
+                    fingerprints: vec![PathBuf::from(format!(".fingerprint/foo-{figureprint}"))],
+                })
+                .into(),
+            dirty_reasons: vec!["dirty: the rustflags changed".to_string()],
+        };
+
+        let json = serde_json::to_value(&explanation).unwrap();
+        let builds = json["builds"].as_array().unwrap();
+        assert_eq!(builds.len(), 2);
+        assert_eq!(builds[1]["figureprint"], "fedcba9876543210");
+        assert_eq!(builds[1]["status"], "outdated");
+        assert_eq!(
+            builds[1]["fingerprints"][0],
+            ".fingerprint/foo-fedcba9876543210"
+        );
+        assert_eq!(json["dirty_reasons"][0], "dirty: the rustflags changed");
+    }
+
+    #[test]
+    fn picks_the_reasons_of_the_crate() {
+        let stderr = [
+            r#"   0.1s  INFO prepare_target{force=false package_id=foo v0.1.0 target="foo"}: cargo::core::compiler::fingerprint: dirty: the file `src/lib.rs` has changed"#,
+            r#"   0.1s  INFO prepare_target{force=false package_id=bar v0.1.0 target="bar"}: cargo::core::compiler::fingerprint: dirty: the rustflags changed"#,
+            "   Compiling foo v0.1.0",
+        ]
+        .join("\n");
+
+        assert_eq!(
+            dirty_reasons(&stderr, "foo"),
+            ["dirty: the file `src/lib.rs` has changed"]
+        );
+        assert!(dirty_reasons(&stderr, "baz").is_empty());
+    }
+}
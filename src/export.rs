@@ -0,0 +1,67 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    args::Args,
+    deps::{self, Artifact},
+    filesystem::OsFileSystem,
+    get_figureprints, Figureprints, Workspace,
+};
+
+/// Scanned state of a profile's deps directory, which `cargo gc stats --import`
+/// can report on elsewhere.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub profile: String,
+    pub artifacts: Vec<Artifact>,
+    /// Figureprints in use, only known if exported with `--build`
+    pub figureprints: Option<Figureprints>,
+}
+
+impl Snapshot {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+        serde_json::from_str(&content).with_context(|| format!("failed to parse {:?}", path))
+    }
+}
+
+pub fn run(
+    args: &Args,
+    workspace: &Workspace,
+    output: Option<&PathBuf>,
+    build: bool,
+) -> Result<()> {
+    let figureprints = if build {
+        Some(get_figureprints(args, workspace)?)
+    } else {
+        None
+    };
+    let deps_path = workspace.profile_path(args).join("deps");
+    let snapshot = Snapshot {
+        profile: args.profile_dir().to_string(),
+        artifacts: deps::scan(&OsFileSystem, &deps_path, args.apparent_size, args.verbose)?,
+        figureprints,
+    };
+
+    let json = serde_json::to_string_pretty(&snapshot).context("failed to serialize snapshot")?;
+    match output {
+        Some(output) => {
+            fs::write(output, json).with_context(|| format!("failed to write {:?}", output))?;
+            eprintln!(
+                "exported {} files of profile {} to {:?}",
+                snapshot.artifacts.len(),
+                snapshot.profile,
+                output
+            );
+        }
+        None => writeln!(io::stdout(), "{json}").context("failed to write to stdout")?,
+    }
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::file_size;
+
+/// What gc reads from the metadata of an entry.
+pub struct EntryMetadata {
+    pub is_dir: bool,
+    pub is_symlink: bool,
+    /// Apparent size and the space allocated on disk, see [`crate::file_size`]
+    pub len: u64,
+    pub allocated: u64,
+    pub modified: SystemTime,
+}
+
+impl EntryMetadata {
+    pub fn size(&self, apparent: bool) -> u64 {
+        if apparent {
+            self.len
+        } else {
+            self.allocated
+        }
+    }
+}
+
+impl From<fs::Metadata> for EntryMetadata {
+    fn from(metadata: fs::Metadata) -> Self {
+        Self {
+            is_dir: metadata.is_dir(),
+            is_symlink: metadata.file_type().is_symlink(),
+            len: file_size(&metadata, true),
+            allocated: file_size(&metadata, false),
+            modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        }
+    }
+}
+
+/// The file system operations scanning and removing go through, so they can
+/// run against [`MemoryFileSystem`] in tests.
+pub trait FileSystem: Sync {
+    /// Paths of the entries in a directory, in no particular order.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+    /// Metadata of `path`, following symlinks.
+    fn metadata(&self, path: &Path) -> io::Result<EntryMetadata>;
+    /// Metadata of `path` itself, even if it's a symlink.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<EntryMetadata>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+}
+
+/// The file system of the OS.
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<EntryMetadata> {
+        fs::metadata(path).map(EntryMetadata::from)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<EntryMetadata> {
+        fs::symlink_metadata(path).map(EntryMetadata::from)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+}
+
+/// A file system in memory without symlinks, holding directories and files
+/// of a given size and modification time.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryFileSystem {
+    /// (Size, Modified) of files, `None` for directories
+    entries: std::sync::Mutex<std::collections::BTreeMap<PathBuf, Option<(u64, SystemTime)>>>,
+    /// Entries listed in their directory that fail to be read
+    unreadable: std::sync::Mutex<std::collections::BTreeSet<PathBuf>>,
+}
+
+#[cfg(test)]
+impl MemoryFileSystem {
+    /// Add a directory and its missing parents.
+    pub fn add_dir(&self, path: impl AsRef<Path>) {
+        let mut entries = self.entries.lock().unwrap();
+        for dir in path.as_ref().ancestors() {
+            if !dir.as_os_str().is_empty() {
+                entries.insert(dir.to_path_buf(), None);
+            }
+        }
+    }
+
+    /// Add a file and its missing parent directories.
+    pub fn add_file(&self, path: impl AsRef<Path>, size: u64, modified: SystemTime) {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            self.add_dir(parent);
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(path.to_path_buf(), Some((size, modified)));
+    }
+
+    /// Add a file that is listed in its directory, but whose metadata can't be
+    /// read.
+    pub fn add_unreadable(&self, path: impl AsRef<Path>) {
+        self.add_file(&path, 0, SystemTime::UNIX_EPOCH);
+        let mut unreadable = self.unreadable.lock().unwrap();
+        unreadable.insert(path.as_ref().to_path_buf());
+    }
+
+    /// Paths of all files and directories, sorted.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.entries.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{:?} not found", path))
+}
+
+#[cfg(test)]
+impl FileSystem for MemoryFileSystem {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(path) {
+            Some(None) => Ok(entries
+                .keys()
+                .filter(|entry| entry.parent() == Some(path))
+                .cloned()
+                .collect()),
+            Some(Some(_)) => Err(io::Error::other(format!("{:?} is not a directory", path))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<EntryMetadata> {
+        if self.unreadable.lock().unwrap().contains(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{:?} can't be read", path),
+            ));
+        }
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(path).ok_or_else(|| not_found(path))?;
+        let (len, modified) = entry.unwrap_or((0, SystemTime::UNIX_EPOCH));
+        Ok(EntryMetadata {
+            is_dir: entry.is_none(),
+            is_symlink: false,
+            len,
+            allocated: len,
+            modified,
+        })
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<EntryMetadata> {
+        self.metadata(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(path) {
+            Some(Some(_)) => {
+                entries.remove(path);
+                Ok(())
+            }
+            Some(None) => Err(io::Error::other(format!("{:?} is a directory", path))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if !matches!(entries.get(path), Some(None)) {
+            return Err(not_found(path));
+        }
+        entries.retain(|entry, _| !entry.starts_with(path));
+        Ok(())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.metadata(path).map(|_| path.to_path_buf())
+    }
+}
@@ -0,0 +1,90 @@
+use std::{collections::HashSet, io};
+
+/// (Device, Inode) of files other processes have open or mapped, like running
+/// executables and the libraries they loaded. Processes that can't be read,
+/// e.g. of other users, are left out.
+#[cfg(target_os = "linux")]
+pub fn open_files() -> HashSet<(u64, u64)> {
+    use std::fs;
+
+    use crate::inode;
+
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return HashSet::new();
+    };
+    let current = std::process::id().to_string();
+    let mut paths = HashSet::new();
+    for process in processes.filter_map(|entry| entry.ok()) {
+        let pid = process.file_name().to_string_lossy().to_string();
+        if pid == current || !pid.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        // The path is the last of the columns, the only one starting with `/`
+        if let Ok(maps) = fs::read_to_string(process.path().join("maps")) {
+            paths.extend(
+                maps.lines()
+                    .filter_map(|line| line.find(" /").map(|start| &line[start + 1..]))
+                    .map(|path| path.trim_end_matches(" (deleted)").to_string()),
+            );
+        }
+        if let Ok(fds) = fs::read_dir(process.path().join("fd")) {
+            paths.extend(
+                fds.filter_map(|fd| fs::read_link(fd.ok()?.path()).ok())
+                    .map(|path| path.to_string_lossy().to_string()),
+            );
+        }
+    }
+    paths
+        .into_iter()
+        .filter(|path| path.starts_with('/'))
+        .filter_map(|path| inode(&fs::metadata(path).ok()?))
+        .collect()
+}
+
+/// Open files aren't known up front elsewhere, removing them fails instead.
+#[cfg(not(target_os = "linux"))]
+pub fn open_files() -> HashSet<(u64, u64)> {
+    HashSet::new()
+}
+
+/// Whether removing a file failed as another process has it open.
+#[cfg(windows)]
+pub fn is_in_use_error(e: &io::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    e.raw_os_error() == Some(ERROR_SHARING_VIOLATION)
+}
+
+/// Open files can be removed, they're gone once closed.
+#[cfg(not(windows))]
+pub fn is_in_use_error(_e: &io::Error) -> bool {
+    false
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{fs, process::Command};
+
+    use super::*;
+    use crate::inode;
+
+    #[test]
+    fn finds_files_other_processes_have_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = dir.path().join("open");
+        let closed = dir.path().join("closed");
+        fs::write(&open, "").unwrap();
+        fs::write(&closed, "").unwrap();
+        let mut child = Command::new("sleep")
+            .arg("60")
+            .stdin(fs::File::open(&open).unwrap())
+            .spawn()
+            .unwrap();
+
+        let open_files = open_files();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let inode = |path| inode(&fs::metadata(path).unwrap()).unwrap();
+        assert!(open_files.contains(&inode(&open)));
+        assert!(!open_files.contains(&inode(&closed)));
+    }
+}
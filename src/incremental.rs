@@ -0,0 +1,397 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    continue_on_error, dir_size, filesystem::FileSystem, is_cache_dir_tagged, warn_unreadable,
+    SizeCounter,
+};
+
+#[derive(Default)]
+pub struct IncrementalAnalysis {
+    /// Number of crates that have incremental data
+    pub crates: usize,
+    /// (Path, Size) of directories to remove
+    pub outdated: Vec<(PathBuf, u64)>,
+    /// Entries skipped as they can't be read
+    pub unreadable: usize,
+}
+
+impl IncrementalAnalysis {
+    pub fn reclaimable_size(&self) -> u64 {
+        self.outdated.iter().map(|(_, size)| size).sum()
+    }
+}
+
+/// Find incremental sessions that can be removed, keeping the `keep(name)`
+/// most recently modified ones of each unit, and any modified within
+/// `max_age`. Entries that can't be read are skipped with a warning, and so is
+/// the whole directory with `keep_going`.
+///
+/// Incremental directories are named `<crate name>-<hash>`, one for each unit.
+/// The lib, bins, tests and build script of a package share the crate name but
+/// not the hash, so they are told apart by the whole directory name. Each of
+/// them holds the compilation sessions of that unit, `s-<...>` directories
+/// with a `.lock` file next to them. rustc removes the lock files of sessions
+/// that are gone the next time it builds the unit.
+pub fn analyze(
+    fs: &dyn FileSystem,
+    incremental_path: &Path,
+    keep: &dyn Fn(&str) -> usize,
+    max_age: Option<Duration>,
+    size_counter: &mut SizeCounter,
+    keep_going: bool,
+) -> Result<IncrementalAnalysis> {
+    let cutoff = max_age.and_then(|max_age| SystemTime::now().checked_sub(max_age));
+    let Some(units) = continue_on_error(keep_going, read_dir(fs, incremental_path))? else {
+        return Ok(IncrementalAnalysis {
+            unreadable: 1,
+            ..IncrementalAnalysis::default()
+        });
+    };
+
+    let mut crates = HashSet::new();
+    let mut outdated = vec![];
+    let mut unreadable = units.unreadable;
+    for (_, unit_path) in units.entries {
+        let dir_name = unit_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let Some(name) = crate_name(&dir_name) else {
+            continue;
+        };
+        crates.insert(name.to_string());
+
+        let mut sessions = match read_dir(fs, &unit_path) {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                warn_unreadable(&unit_path, format!("{e:#}"));
+                unreadable += 1;
+                continue;
+            }
+        };
+        unreadable += sessions.unreadable;
+        // Sessions being compiled are named `s-<...>-working`
+        sessions.entries.retain(|(_, path)| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with("s-") && !name.ends_with("-working")
+        });
+        // Newest first. Break ties by path so the same session is kept on every run
+        sessions
+            .entries
+            .sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        let old_sessions = sessions
+            .entries
+            .into_iter()
+            .skip(keep(name))
+            .filter(|(modified, _)| cutoff.is_none_or(|cutoff| *modified < cutoff));
+        outdated.extend(old_sessions.map(|(_, path)| {
+            let size = dir_size(&path, size_counter);
+            (path, size)
+        }));
+    }
+    Ok(IncrementalAnalysis {
+        crates: crates.len(),
+        outdated,
+        unreadable,
+    })
+}
+
+/// Subdirectories of a directory that aren't tagged as caches.
+struct Subdirectories {
+    /// (Modified, Path) of each subdirectory
+    entries: Vec<(SystemTime, PathBuf)>,
+    /// Entries skipped as they can't be read
+    unreadable: usize,
+}
+
+fn read_dir(fs: &dyn FileSystem, path: &Path) -> Result<Subdirectories> {
+    let entries = fs
+        .read_dir(path)
+        .with_context(|| format!("failed to read incremental directory: {:?}", path))?;
+    let mut subdirectories = Subdirectories {
+        entries: vec![],
+        unreadable: 0,
+    };
+    for entry in entries {
+        let metadata = match fs.symlink_metadata(&entry) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                warn_unreadable(&entry, e);
+                subdirectories.unreadable += 1;
+                continue;
+            }
+        };
+        if !metadata.is_dir || is_cache_dir_tagged(&entry) {
+            continue;
+        }
+        subdirectories.entries.push((metadata.modified, entry));
+    }
+    Ok(subdirectories)
+}
+
+/// Crate name of an incremental directory, `None` for anything not named like
+/// `<crate name>-<base 36 hash>`.
+pub fn crate_name(dir_name: &str) -> Option<&str> {
+    let (name, hash) = dir_name.rsplit_once('-')?;
+    let is_hash = !hash.is_empty() && hash.bytes().all(|b| b.is_ascii_alphanumeric());
+    (!name.is_empty() && is_hash).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use filetime::FileTime;
+
+    use super::*;
+    use crate::filesystem::OsFileSystem;
+
+    /// Create the session directory `unit/session`, modified `age` seconds ago.
+    fn session(incremental_path: &Path, unit: &str, session: &str, age: u64) -> PathBuf {
+        let path = incremental_path.join(unit).join(session);
+        fs::create_dir_all(&path).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(age);
+        filetime::set_file_mtime(&path, FileTime::from_system_time(modified)).unwrap();
+        path
+    }
+
+    fn outdated(incremental_path: &Path, keep: usize) -> Vec<PathBuf> {
+        let analysis = analyze(
+            &OsFileSystem,
+            incremental_path,
+            &|_| keep,
+            None,
+            &mut SizeCounter::new(false),
+            false,
+        )
+        .unwrap();
+        let mut outdated = analysis
+            .outdated
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        outdated.sort();
+        outdated
+    }
+
+    #[test]
+    fn units_of_one_crate_are_kept_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        // The lib, bin and test units of crate `foo`, and two build scripts
+        session(
+            dir.path(),
+            "foo-0w2sozlfdr77a",
+            "s-hn9tox5q3z-1juwv4w-61hz",
+            10,
+        );
+        session(
+            dir.path(),
+            "foo-10xcqcvhffb7x",
+            "s-hn9towyqtv-1onhqm9-03us",
+            20,
+        );
+        session(
+            dir.path(),
+            "foo-2u1o25emh4pvg",
+            "s-hn9towy58b-05vx094-0i3m",
+            30,
+        );
+        session(
+            dir.path(),
+            "build_script_build-0g1vz1dexvqdo",
+            "s-hn9t-0frp-csg9",
+            10,
+        );
+        session(
+            dir.path(),
+            "build_script_build-1h2vz1dexvqdo",
+            "s-hn9u-0frp-csg9",
+            20,
+        );
+
+        assert!(outdated(dir.path(), 1).is_empty());
+    }
+
+    #[test]
+    fn keeps_newest_sessions_of_each_unit() {
+        let dir = tempfile::tempdir().unwrap();
+        session(dir.path(), "foo-0w2sozlfdr77a", "s-newest-1juwv4w-61hz", 10);
+        session(dir.path(), "foo-0w2sozlfdr77a", "s-newer-1onhqm9-03us", 20);
+        let oldest = session(dir.path(), "foo-0w2sozlfdr77a", "s-oldest-05vx094-0i3m", 30);
+        session(dir.path(), "foo-10xcqcvhffb7x", "s-other-0frpqgj-csg9", 40);
+
+        assert_eq!(outdated(dir.path(), 2), [oldest]);
+    }
+
+    #[test]
+    fn keep_counts_sessions_of_each_unit() {
+        let dir = tempfile::tempdir().unwrap();
+        session(dir.path(), "foo-0w2sozlfdr77a", "s-newer-1juwv4w-61hz", 10);
+        let lib = session(dir.path(), "foo-0w2sozlfdr77a", "s-older-1onhqm9-03us", 20);
+        session(dir.path(), "foo-10xcqcvhffb7x", "s-newer-05vx094-0i3m", 30);
+        let bin = session(dir.path(), "foo-10xcqcvhffb7x", "s-older-0frpqgj-csg9", 40);
+
+        assert_eq!(outdated(dir.path(), 1), [lib, bin]);
+    }
+
+    #[test]
+    fn reads_cargo_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit = dir.path().join("foo-0w2sozlfdr77a");
+        session(
+            dir.path(),
+            "foo-0w2sozlfdr77a",
+            "s-hn9tox5q3z-1juwv4w-61hz",
+            10,
+        );
+        fs::write(unit.join("s-hn9tox5q3z-1juwv4w.lock"), "").unwrap();
+        let old = session(
+            dir.path(),
+            "foo-0w2sozlfdr77a",
+            "s-hn9tow0000-0frpqgj-csg9",
+            20,
+        );
+        fs::write(unit.join("s-hn9tow0000-0frpqgj.lock"), "").unwrap();
+        // Being compiled right now
+        session(
+            dir.path(),
+            "foo-0w2sozlfdr77a",
+            "s-hn9tow0001-05vx094-working",
+            30,
+        );
+        // Not a unit or not a session
+        session(dir.path(), "foo", "s-hn9tow0002-1onhqm9-03us", 40);
+        session(dir.path(), "foo-0w2sozlfdr77a", "other", 50);
+        fs::write(dir.path().join(".DS_Store"), "").unwrap();
+        fs::write(dir.path().join("bar-10xcqcvhffb7x"), "").unwrap();
+
+        assert_eq!(outdated(dir.path(), 1), [old]);
+    }
+
+    #[test]
+    fn skips_units_tagged_as_caches() {
+        let dir = tempfile::tempdir().unwrap();
+        session(dir.path(), "foo-0w2sozlfdr77a", "s-newer-1juwv4w-61hz", 10);
+        session(dir.path(), "foo-0w2sozlfdr77a", "s-older-1onhqm9-03us", 20);
+        fs::write(
+            dir.path().join("foo-0w2sozlfdr77a/CACHEDIR.TAG"),
+            "Signature: 8a477f597d28d172789f06886806bc55\n",
+        )
+        .unwrap();
+
+        assert!(outdated(dir.path(), 1).is_empty());
+    }
+
+    #[test]
+    fn max_age_keeps_recent_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let hour = 60 * 60;
+        session(
+            dir.path(),
+            "foo-0w2sozlfdr77a",
+            "s-newest-1juwv4w-61hz",
+            hour,
+        );
+        let recent = session(
+            dir.path(),
+            "foo-0w2sozlfdr77a",
+            "s-recent-1onhqm9-03us",
+            2 * hour,
+        );
+        let old = session(
+            dir.path(),
+            "foo-0w2sozlfdr77a",
+            "s-old-05vx094-0i3m",
+            72 * hour,
+        );
+        // The newest session is kept however old it is
+        session(
+            dir.path(),
+            "foo-10xcqcvhffb7x",
+            "s-only-0frpqgj-csg9",
+            120 * hour,
+        );
+
+        let analysis = analyze(
+            &OsFileSystem,
+            dir.path(),
+            &|_| 1,
+            Some(Duration::from_secs(24 * hour)),
+            &mut SizeCounter::new(false),
+            false,
+        )
+        .unwrap();
+        let with_max_age = analysis
+            .outdated
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(with_max_age, [old.as_path()]);
+        assert_eq!(outdated(dir.path(), 1), [old, recent]);
+    }
+
+    #[test]
+    fn goes_on_past_unreadable_units() {
+        use crate::filesystem::MemoryFileSystem;
+
+        let fs = MemoryFileSystem::default();
+        let incremental = Path::new("/target/debug/incremental");
+        // Directories are all as old, the first session by path is kept
+        fs.add_dir(incremental.join("foo-0w2sozlfdr77a/s-first-1juwv4w-61hz"));
+        fs.add_dir(incremental.join("foo-0w2sozlfdr77a/s-second-1onhqm9-03us"));
+        fs.add_dir(incremental.join("bar-10xcqcvhffb7x/s-only-0frpqgj-csg9"));
+        fs.add_unreadable(incremental.join("baz-2u1o25emh4pvg"));
+
+        let analysis = analyze(
+            &fs,
+            incremental,
+            &|_| 1,
+            None,
+            &mut SizeCounter::new(false),
+            false,
+        )
+        .unwrap();
+        assert_eq!(analysis.unreadable, 1);
+        assert_eq!(analysis.crates, 2);
+        let outdated = analysis
+            .outdated
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outdated,
+            [incremental.join("foo-0w2sozlfdr77a/s-second-1onhqm9-03us")]
+        );
+    }
+
+    #[test]
+    fn keep_going_skips_an_unreadable_incremental_directory() {
+        use crate::filesystem::MemoryFileSystem;
+
+        let fs = MemoryFileSystem::default();
+        let incremental = Path::new("/target/debug/incremental");
+        fs.add_file(incremental, 0, SystemTime::UNIX_EPOCH);
+
+        let analyze = |keep_going| {
+            analyze(
+                &fs,
+                incremental,
+                &|_| 1,
+                None,
+                &mut SizeCounter::new(false),
+                keep_going,
+            )
+        };
+        assert!(analyze(false).is_err());
+        let analysis = analyze(true).unwrap();
+        assert_eq!(analysis.unreadable, 1);
+        assert!(analysis.outdated.is_empty());
+    }
+}
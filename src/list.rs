@@ -0,0 +1,106 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    args::{Args, Kind},
+    plan, Plan,
+};
+
+/// Print removal candidates relative to the target directory, one per line or
+/// separated by NUL with `null`.
+pub fn run(args: &Args, null: bool, kind: Option<Kind>) -> Result<()> {
+    // Nothing is measured, only paths are listed
+    let mut plan = plan(args, false)?;
+    if let Some(kind) = kind {
+        retain_kind(&mut plan, kind);
+    }
+    let separator = if null { b'\0' } else { b'\n' };
+
+    let paths = plan
+        .files
+        .iter()
+        .map(|artifact| artifact.path.as_path())
+        .chain(plan.unit_dirs.iter().map(|(path, _)| path.as_path()))
+        .chain(
+            plan.incremental
+                .outdated
+                .iter()
+                .map(|(path, _)| path.as_path()),
+        )
+        .chain(plan.removed_crates.iter().map(|(path, _)| path.as_path()));
+    let mut stdout = io::stdout().lock();
+    for path in paths {
+        let relative = path.strip_prefix(&plan.target_directory).unwrap_or(path);
+        match write_path(&mut stdout, relative, separator) {
+            Ok(()) => {}
+            // The reader has seen enough, e.g. `cargo gc list | head`
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e).context("failed to write to stdout"),
+        }
+    }
+    Ok(())
+}
+
+/// Drop the candidates not of `kind`.
+fn retain_kind(plan: &mut Plan, kind: Kind) {
+    if kind != Kind::Deps {
+        plan.files.clear();
+    }
+    if kind != Kind::Fingerprint {
+        plan.unit_dirs.clear();
+    }
+    if kind != Kind::Incremental {
+        plan.incremental.outdated.clear();
+    }
+    // Directories of removed crates are in `.fingerprint`, `build` and `incremental`
+    let profile_path = plan.profile_path.clone();
+    plan.removed_crates.retain(|(path, _)| {
+        let in_incremental = path.starts_with(profile_path.join("incremental"));
+        match kind {
+            Kind::Deps => false,
+            Kind::Incremental => in_incremental,
+            Kind::Fingerprint => !in_incremental,
+        }
+    });
+}
+
+fn write_path(writer: &mut impl Write, path: &Path, separator: u8) -> io::Result<()> {
+    writer.write_all(path.as_os_str().as_encoded_bytes())?;
+    writer.write_all(&[separator])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(paths: &[&str], separator: u8) -> Vec<u8> {
+        let mut output = vec![];
+        for path in paths {
+            write_path(&mut output, Path::new(path), separator).unwrap();
+        }
+        output
+    }
+
+    #[test]
+    fn separates_paths_by_newline() {
+        assert_eq!(
+            written(
+                &["debug/deps/libfoo-0123.rlib", "debug/incremental/foo-1a"],
+                b'\n'
+            ),
+            b"debug/deps/libfoo-0123.rlib\ndebug/incremental/foo-1a\n"
+        );
+    }
+
+    #[test]
+    fn separates_paths_by_null() {
+        assert_eq!(
+            written(&["debug/deps/a b", "debug/deps/c\nd"], b'\0'),
+            b"debug/deps/a b\0debug/deps/c\nd\0"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::{collections::HashSet, fs};
+
+use anyhow::{Context, Result};
+use cargo_metadata::Metadata;
+use serde::Deserialize;
+
+use crate::normalize_crate_name;
+
+/// A package in `Cargo.lock`.
+#[derive(Deserialize)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    /// Like `registry+https://...` or `git+https://...#<commit>`, `None` for
+    /// path dependencies
+    pub source: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+/// Packages in the lockfile of the workspace `metadata` describes. Unlike the
+/// packages of `metadata`, they include optional dependencies of features that
+/// aren't enabled, which the workspace still builds with other features.
+pub fn packages(metadata: &Metadata) -> Result<Vec<LockedPackage>> {
+    let path = metadata.workspace_root.join("Cargo.lock");
+    let content =
+        fs::read_to_string(&path).with_context(|| format!("failed to read {:?}", path))?;
+    let lockfile: Lockfile =
+        toml::from_str(&content).with_context(|| format!("failed to parse {:?}", path))?;
+    Ok(lockfile.package)
+}
+
+/// Normalized names of all packages in the lockfile and the crates they build.
+/// Targets named apart from their package are only known for the packages of
+/// `metadata`.
+pub fn live_crates(metadata: &Metadata) -> Result<HashSet<String>> {
+    let mut live_crates = packages(metadata)?
+        .iter()
+        .map(|package| normalize_crate_name(&package.name))
+        .collect::<HashSet<_>>();
+    live_crates.extend(
+        metadata
+            .packages
+            .iter()
+            .flat_map(|package| &package.targets)
+            .map(|target| normalize_crate_name(&target.name)),
+    );
+    Ok(live_crates)
+}
+
+#[cfg(test)]
+mod tests {
+    use cargo_metadata::MetadataCommand;
+
+    use super::*;
+
+    #[test]
+    fn optional_dependencies_are_live() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Cargo.toml"),
+            r#"
+            [package]
+            name = "app"
+            version = "0.1.0"
+            edition = "2021"
+
+            [dependencies]
+            optional-dep = { path = "optional-dep", optional = true }
+            "#,
+        )
+        .unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+        fs::create_dir_all(dir.path().join("optional-dep/src")).unwrap();
+        fs::write(
+            dir.path().join("optional-dep/Cargo.toml"),
+            r#"
+            [package]
+            name = "optional-dep"
+            version = "0.1.0"
+            edition = "2021"
+            "#,
+        )
+        .unwrap();
+        fs::write(dir.path().join("optional-dep/src/lib.rs"), "").unwrap();
+
+        let metadata = MetadataCommand::new()
+            .manifest_path(dir.path().join("Cargo.toml"))
+            .other_options(vec!["--offline".to_string()])
+            .exec()
+            .unwrap();
+        assert!(!metadata
+            .packages
+            .iter()
+            .any(|package| package.name == "optional-dep"));
+
+        let live_crates = live_crates(&metadata).unwrap();
+        assert!(live_crates.contains("app"));
+        assert!(live_crates.contains("optional_dep"));
+    }
+}
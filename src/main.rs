@@ -1,23 +1,89 @@
+/// Print a line of the result for people to stdout, or to stderr with
+/// `--format json` where stdout is for the JSON. Nothing is printed with
+/// `--quiet`.
+macro_rules! report {
+    ($args:expr, $($arg:tt)*) => {
+        if !$args.quiet {
+            match $args.format {
+                $crate::args::Format::Text => println!($($arg)*),
+                $crate::args::Format::Json => eprintln!($($arg)*),
+            }
+        }
+    };
+}
+
 mod args;
+mod build_target;
+mod cache;
+mod dedup;
+mod deps;
+mod diff;
+mod doc;
+mod doctor;
+mod explain;
+mod export;
+mod filesystem;
+mod in_use;
+mod incremental;
+mod list;
+mod lockfile;
+mod metrics;
+mod policy;
+mod profile;
+mod prune;
+mod rustc;
+mod saved_plan;
+mod since;
+mod stats;
 
-use std::{collections::HashSet, fs, path::PathBuf, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::{self, File, Metadata, TryLockError},
+    io,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::{Context, Result};
-use args::{Args, Cli};
+use args::{Args, Cli, Color, Format, Mode};
+use cache::CacheReport;
 use cargo_metadata::MetadataCommand;
-use clap::Parser;
+use clap::CommandFactory;
+use console::{style, Term};
+use dedup::DedupReport;
+use deps::Artifact;
+use filesystem::{FileSystem, OsFileSystem};
+use glob::Pattern;
 use humansize::DECIMAL;
-use indicatif::ProgressBar;
-use serde::Deserialize;
+use incremental::IncrementalAnalysis;
+use indicatif::{ProgressBar, ProgressStyle};
+use policy::Policy;
+use serde::{Deserialize, Serialize};
 
 type Figureprints = HashSet<(String, String)>;
 
 struct OutputCollection {
-    /// (Names, Fingerprints)
+    /// (Names, Fingerprints) of files in deps and directories in build
     deps_figureprints: Figureprints,
+    units: UnitCounts,
+}
+
+/// Units of a build by whether cargo found them up to date.
+#[derive(Serialize, Clone, Copy, Default, Debug, PartialEq)]
+struct UnitCounts {
+    fresh: usize,
+    dirty: usize,
 }
 
 impl OutputCollection {
+    /// Paths aren't always UTF-8. Replacing invalid bytes keeps the JSON valid
+    /// as they only appear inside strings.
+    fn from_stdout(stdout: &[u8]) -> Result<Self> {
+        Self::from_json(&String::from_utf8_lossy(stdout))
+    }
+
     fn from_json(json: &str) -> Result<Self> {
         let result = json
             .lines()
@@ -25,17 +91,23 @@ impl OutputCollection {
             .collect::<Result<Vec<OutputItem>>>()?;
 
         let mut set = HashSet::new();
+        let mut units = UnitCounts::default();
         for item in result {
+            match item.fresh {
+                Some(true) => units.fresh += 1,
+                Some(false) => units.dirty += 1,
+                None => {}
+            }
+            // Build scripts are compiled into and run in `build/<package>-<figureprint>`
+            for path in item.filenames.iter().flatten().chain(&item.out_dir) {
+                if let Some(build) = build_script_figureprint(Path::new(path)) {
+                    set.insert(build);
+                }
+            }
             for name in item.filenames.unwrap_or_default() {
-                let path = PathBuf::from(name);
-                let file_stem = path
-                    .file_stem()
-                    .unwrap_or_default()
-                    .to_string_lossy()
-                    .to_string();
-                if file_stem.is_empty() {
+                let Some(file_stem) = artifact_stem(Path::new(&name)) else {
                     continue;
-                }
+                };
                 if let Some((name, figureprint)) = extract_figureprint(&file_stem) {
                     set.insert((name.to_string(), figureprint.to_string()));
                 }
@@ -48,132 +120,2273 @@ impl OutputCollection {
         }
         Ok(Self {
             deps_figureprints: set,
+            units,
         })
     }
 }
 
+/// Name and figureprint of the `build/<package>-<figureprint>` directory a
+/// path is in, if any.
+fn build_script_figureprint(path: &Path) -> Option<(String, String)> {
+    path.ancestors()
+        .find(|dir| {
+            dir.parent()
+                .and_then(Path::file_name)
+                .is_some_and(|name| name == "build")
+        })
+        .and_then(|dir| extract_figureprint(&dir.file_name()?.to_string_lossy()))
+}
+
+/// File name without any extension. Versioned shared libraries like
+/// `libfoo-<figureprint>.so.1.2.3` have several, while crate names and
+/// figureprints never contain a dot.
+fn artifact_stem(path: &Path) -> Option<String> {
+    let file_name = path.file_name()?.to_string_lossy();
+    let stem = file_name.split('.').next().unwrap_or_default();
+    (!stem.is_empty()).then(|| stem.to_string())
+}
+
+/// Split a file stem like `libserde-1d2e3f4a5b6c7d8e` into crate name and
+/// figureprint. Cargo's figureprint is a 64-bit hash in 16 hex digits, stems
+/// without one are not build artifacts.
 fn extract_figureprint(file_stem: &str) -> Option<(String, String)> {
-    file_stem
-        .rsplit_once('-')
-        .map(|(name, figureprint)| (name.to_string(), figureprint.to_string()))
+    let (name, figureprint) = file_stem.rsplit_once('-')?;
+    if name.is_empty()
+        || figureprint.len() != 16
+        || !figureprint.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return None;
+    }
+    Some((normalize_crate_name(name), figureprint.to_string()))
+}
+
+/// Package names may contain dashes while crate names use underscores, e.g.
+/// `tracing-subscriber` is built into `libtracing_subscriber-<figureprint>`.
+fn normalize_crate_name(name: &str) -> String {
+    name.replace('-', "_")
 }
 
 #[derive(Deserialize, Default)]
 struct OutputItem {
     filenames: Option<Vec<String>>,
+    /// Set for executed build scripts
+    out_dir: Option<String>,
+    /// Set for compiled units
+    fresh: Option<bool>,
 }
 
-fn get_figureprints(args: &Args) -> Result<Figureprints> {
-    let spinner = ProgressBar::new_spinner();
+fn get_figureprints(args: &Args, workspace: &Workspace) -> Result<Figureprints> {
+    build(args, workspace, &[]).map(|(collection, _)| collection.deps_figureprints)
+}
+
+/// Run `cargo build` with extra environment variables, returning what it
+/// built and its stderr.
+fn build(
+    args: &Args,
+    workspace: &Workspace,
+    envs: &[(&str, &str)],
+) -> Result<(OutputCollection, String)> {
+    // The spinner draws to stderr, and only when it's a terminal
+    let spinner = if args.quiet || args.no_progress {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new_spinner()
+    };
     spinner.set_message("running cargo build to gather message...");
     spinner.enable_steady_tick(Duration::from_millis(100));
-    let output = std::process::Command::new("cargo")
+    // Use the cargo that invokes us, like `cargo metadata` does. Other settings
+    // like `RUSTC`, `RUSTC_WRAPPER` and `RUSTUP_TOOLCHAIN` are inherited from
+    // the environment, so the build sees the same toolchain as the user's.
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut command = std::process::Command::new(cargo);
+    command
         .args(["build", "--message-format=json"])
         .args(args.cargo_profile_args())
-        .args(&args.cargo_args)
-        .output()
-        .context("failed to execute cargo build")?;
+        .args(args.cargo_lock_args())
+        .arg("--manifest-path")
+        .arg(&workspace.manifest_path)
+        .envs(envs.iter().copied());
+    if args.host {
+        // `build.target` in cargo's config can't be unset, the host
+        // directory only has the host units of that build then
+        command
+            .env_remove("CARGO_BUILD_TARGET")
+            .args(build_target::without_target(&args.cargo_args));
+    } else {
+        command.args(&args.cargo_args);
+    }
+    let output = command.output().context("failed to execute cargo build")?;
     spinner.finish_and_clear();
 
     // check cargo build result
     if !output.status.success() {
-        let stderr = String::from_utf8(output.stderr).context("failed to parse stderr")?;
-        return Err(anyhow::anyhow!("cargo build failed: {}", stderr));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(BuildFailed(stderr.to_string()).into());
+    }
+
+    let collection = OutputCollection::from_stdout(&output.stdout)?;
+    Ok((
+        collection,
+        String::from_utf8_lossy(&output.stderr).to_string(),
+    ))
+}
+
+/// Scan the artifacts in `deps` and `examples` of the profile directory.
+fn scan_artifacts(args: &Args, profile_path: &Path) -> Result<deps::Scan> {
+    let mut scan = deps::scan_with_progress(
+        &OsFileSystem,
+        &profile_path.join("deps"),
+        args.apparent_size,
+        args.verbose,
+        &scan_progress(args),
+    )?;
+    // Examples are built into their own directory instead
+    let examples = deps::scan_with_progress(
+        &OsFileSystem,
+        &profile_path.join("examples"),
+        args.apparent_size,
+        args.verbose,
+        &ProgressBar::hidden(),
+    )?;
+    scan.artifacts.extend(examples.artifacts);
+    scan.unreadable += examples.unreadable;
+    Ok(scan)
+}
+
+/// Progress bar of scanning a deps directory, drawn like the build spinner.
+fn scan_progress(args: &Args) -> ProgressBar {
+    if args.quiet || args.no_progress {
+        return ProgressBar::hidden();
+    }
+    ProgressBar::new(0).with_style(
+        ProgressStyle::with_template("{spinner} scanning deps {pos}/{len} files")
+            .expect("progress template is valid"),
+    )
+}
+
+/// Acquire the same lock cargo takes on the profile directory while building.
+fn lock_build_directory(profile_path: &Path) -> Result<File> {
+    let lock_path = profile_path.join(".cargo-lock");
+    let lock = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .with_context(|| format!("failed to open build lock {:?}", lock_path))?;
+    match lock.try_lock() {
+        Ok(()) => Ok(lock),
+        Err(TryLockError::WouldBlock) => Err(anyhow::anyhow!(
+            "another cargo process is building in {:?}, try again after it finishes",
+            profile_path
+        )),
+        Err(TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("failed to acquire build lock {:?}", lock_path))
+        }
     }
+}
 
-    let stdout = String::from_utf8(output.stdout).context("failed to parse stdout")?;
-    let collection = OutputCollection::from_json(&stdout)?;
-    Ok(collection.deps_figureprints)
+fn metadata_command(args: &Args) -> MetadataCommand {
+    let mut command = MetadataCommand::new();
+    command.other_options(args.cargo_lock_args());
+    if let Some(manifest_path) = &args.manifest_path {
+        command.manifest_path(manifest_path);
+    }
+    command
 }
 
-fn main() -> Result<()> {
-    let args = Args::from_cli(Cli::parse());
+/// The workspace to GC. It's always the whole workspace even if gc is invoked
+/// in a member, as all members share the same target directory.
+struct Workspace {
+    /// Path to Cargo.toml at the workspace root
+    manifest_path: PathBuf,
+    target_directory: PathBuf,
+    /// Target triple artifacts are built for if not the host, see
+    /// [`build_target::build_target`]
+    build_target: Option<String>,
+    /// Settings of members in `[package.metadata.cargo-gc]`
+    policy: Policy,
+    /// Crates to GC with `--since`, all crates if `None`
+    changed_crates: Option<HashSet<String>>,
+}
+
+impl Workspace {
+    /// Directory holding the profiles, `target/<triple>` when building for a
+    /// target other than the host.
+    fn profiles_directory(&self) -> PathBuf {
+        match &self.build_target {
+            Some(target) => self.target_directory.join(target),
+            None => self.target_directory.clone(),
+        }
+    }
+
+    fn profile_path(&self, args: &Args) -> PathBuf {
+        self.profiles_directory().join(args.profile_dir())
+    }
+}
 
-    let figureprints = get_figureprints(&args)?;
-    let metadata = MetadataCommand::new()
+fn workspace(args: &Args) -> Result<Workspace> {
+    let metadata = metadata_command(args)
         .no_deps()
         .exec()
         .context("failed to retrieve cargo metadata")?;
-    let target_path = metadata.target_directory;
-    let profile_path = target_path.join(args.profile);
-    let deps_path = profile_path.join("deps");
-    let files_iter = fs::read_dir(deps_path.clone())
-        .with_context(|| format!("failed to read deps directory: {:?}", deps_path))?;
-
-    let mut files_to_remove = HashSet::new();
-    // Find the newest file for each crate
-    for file in files_iter {
-        let file = file.with_context(|| format!("failed to read file in {:?}", deps_path))?;
-        if file
-            .file_type()
-            .context("failed to get fs entry type")?
-            .is_dir()
-        {
-            continue;
+    let manifest_path = metadata
+        .workspace_root
+        .join("Cargo.toml")
+        .into_std_path_buf();
+    profile::check_defined(&args.profiles, &manifest_path)?;
+    Ok(Workspace {
+        manifest_path,
+        target_directory: target_directory(&OsFileSystem, &metadata)?,
+        build_target: if args.host {
+            None
+        } else {
+            build_target::build_target(&args.cargo_args)?
+        },
+        policy: Policy::from_packages(&metadata.packages, &args.exclude_members)?,
+        changed_crates: args
+            .since
+            .as_ref()
+            .map(|since| {
+                since::changed_crates(
+                    metadata.workspace_root.as_std_path(),
+                    &metadata.packages,
+                    since,
+                )
+            })
+            .transpose()?,
+    })
+}
+
+/// The target directory of the workspace. Cargo reports an absolute path, but
+/// anything relative is taken as relative to the workspace root like cargo
+/// does for `build.target-dir` in a workspace's config, not to the current
+/// directory which can be a member.
+///
+/// A target directory symlinked elsewhere, like to a ramdisk, is resolved so
+/// everything is scanned and removed under the real one.
+fn target_directory(fs: &dyn FileSystem, metadata: &cargo_metadata::Metadata) -> Result<PathBuf> {
+    let path = metadata
+        .workspace_root
+        .join(&metadata.target_directory)
+        .into_std_path_buf();
+    if !fs
+        .symlink_metadata(&path)
+        .is_ok_and(|metadata| metadata.is_symlink)
+    {
+        return Ok(path);
+    }
+    let real_path = fs
+        .canonicalize(&path)
+        .with_context(|| format!("failed to resolve symlinked target directory {:?}", path))?;
+    if !fs
+        .metadata(&real_path)
+        .is_ok_and(|metadata| metadata.is_dir)
+    {
+        anyhow::bail!(
+            "target directory {:?} links to {:?}, which is not a directory",
+            path,
+            real_path
+        );
+    }
+    Ok(real_path)
+}
+
+/// `$CARGO_HOME`, or `.cargo` in the home directory.
+fn cargo_home() -> Result<PathBuf> {
+    if let Some(cargo_home) = env::var_os("CARGO_HOME") {
+        return Ok(PathBuf::from(cargo_home));
+    }
+    let home = env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .context("cannot find the home directory, set CARGO_HOME instead")?;
+    Ok(PathBuf::from(home).join(".cargo"))
+}
+
+/// Exit codes besides success, which is 0 even if nothing is removed. Usage
+/// errors exit with 2 as reported by clap.
+mod exit_code {
+    pub const ERROR: u8 = 1;
+    pub const USAGE: u8 = 2;
+    pub const REMOVAL_FAILED: u8 = 3;
+    pub const BUILD_FAILED: u8 = 4;
+    pub const CHECK_FAILED: u8 = 5;
+}
+
+/// `cargo build` exited with failure, holding its stderr.
+#[derive(Debug)]
+struct BuildFailed(String);
+
+impl std::fmt::Display for BuildFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cargo build failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for BuildFailed {}
+
+/// An argument turned out invalid after parsing the command line, exiting
+/// like clap does for invalid ones.
+#[derive(Debug)]
+struct UsageError(String);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            if e.is::<BuildFailed>() {
+                ExitCode::from(exit_code::BUILD_FAILED)
+            } else if e.is::<UsageError>() {
+                ExitCode::from(exit_code::USAGE)
+            } else {
+                ExitCode::from(exit_code::ERROR)
+            }
         }
+    }
+}
 
-        let path = file.path();
-        let ext = path
-            .extension()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .to_string();
-        let full_file_path = path
-            .canonicalize()
-            .with_context(|| format!("cannot canonicalize path {path:?}"))?
-            .to_string_lossy()
-            .to_string();
-        let stem = path
-            .file_stem()
-            .with_context(|| format!("cannot get file stem of {path:?}"))?
-            .to_string_lossy()
-            .to_string();
-        let (name, figureprint) = extract_figureprint(&stem).with_context(|| {
-            format!(
-            "invalid file name: {}, files under deps should contains crate name and figureprint",
-            stem
-        )
-        })?;
-
-        if !figureprints.contains(&(name, figureprint)) && ext != "d" {
-            files_to_remove.insert(full_file_path.clone());
-        }
-    }
-
-    println!("found {} outdated files", files_to_remove.len());
-    if args.verbose {
-        println!("files to remove {files_to_remove:#?}");
+fn run() -> Result<ExitCode> {
+    let args = Args::from_cli(Cli::parse_argv());
+    set_color(args.color);
+
+    let needs_project = !matches!(
+        args.mode,
+        Mode::Diff { .. }
+            | Mode::Completions(_)
+            | Mode::Stats {
+                import: Some(_),
+                ..
+            }
+    ) && args.apply_plan.is_none();
+    if needs_project && args.manifest_path.is_none() && !is_inside_project()? {
+        eprintln!("Error: not inside a Cargo project (no Cargo.toml found)");
+        return Ok(ExitCode::from(exit_code::ERROR));
+    }
+
+    match &args.mode {
+        Mode::Gc => gc_profiles(&args),
+        Mode::Stats {
+            import: Some(snapshot),
+            ..
+        } => stats::replay(&args, snapshot).map(|_| ExitCode::SUCCESS),
+        Mode::Stats {
+            all_profiles,
+            build,
+            import: None,
+        } => {
+            stats::run(&args, &workspace(&args)?, *all_profiles, *build).map(|_| ExitCode::SUCCESS)
+        }
+        Mode::Export { output, build } => {
+            export::run(&args, &workspace(&args)?, output.as_ref(), *build)
+                .map(|_| ExitCode::SUCCESS)
+        }
+        Mode::List { null, kind } => list::run(&args, *null, *kind).map(|_| ExitCode::SUCCESS),
+        Mode::Doc => doc::run(&args).map(|_| ExitCode::SUCCESS),
+        Mode::Completions(shell) => {
+            let mut command = Cli::command();
+            clap_complete::generate(*shell, &mut command, "cargo-gc", &mut io::stdout());
+            Ok(ExitCode::SUCCESS)
+        }
+        Mode::Diff { old, new } => diff::run(&args, old, new).map(|_| ExitCode::SUCCESS),
+        Mode::Doctor => doctor::run(&args, &workspace(&args)?).map(|_| ExitCode::SUCCESS),
+        Mode::Explain(name) => {
+            explain::run(&args, &workspace(&args)?, name).map(|_| ExitCode::SUCCESS)
+        }
+        Mode::Cache { projects, apply } => {
+            cache::run(&args, projects, *apply).map(|_| ExitCode::SUCCESS)
+        }
+    }
+}
+
+/// Whether the current directory or any parent has a Cargo.toml, like cargo
+/// looks for the manifest.
+fn is_inside_project() -> Result<bool> {
+    let current_dir = env::current_dir().context("failed to get the current directory")?;
+    Ok(current_dir
+        .ancestors()
+        .any(|dir| dir.join("Cargo.toml").is_file()))
+}
+
+fn directory_modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Fail if the deps directory was modified since `deps_modified`, nothing to
+/// check without it.
+fn ensure_unchanged(deps_path: &Path, deps_modified: Option<SystemTime>) -> Result<()> {
+    if deps_modified.is_some() && directory_modified(deps_path) != deps_modified {
+        anyhow::bail!(
+            "{:?} changed after the build, another cargo may be running, please retry",
+            deps_path
+        );
+    }
+    Ok(())
+}
+
+/// Follow `--color` like cargo does. `auto` colors a terminal unless
+/// `NO_COLOR` is set, see https://no-color.org.
+fn set_color(color: Color) {
+    let enabled = match color {
+        Color::Always => true,
+        Color::Never => false,
+        Color::Auto if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) => false,
+        Color::Auto => return,
+    };
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
+/// Outdated artifacts found in the profile directory.
+struct Plan {
+    target_directory: PathBuf,
+    profile_path: PathBuf,
+    files: Vec<Artifact>,
+    incremental: IncrementalAnalysis,
+    /// (Path, Size) of `.fingerprint` directories of the outdated files, and
+    /// `build` directories of outdated build scripts with their fingerprints
+    unit_dirs: Vec<(PathBuf, u64)>,
+    /// (Path, Size) of directories of crates not in Cargo.lock
+    removed_crates: Vec<(PathBuf, u64)>,
+    /// Entries of deps, `.fingerprint` and `build` skipped as they can't be
+    /// read, those of incremental are in `incremental`
+    unreadable: usize,
+    /// Units of the build telling what's in use, if gc ran one
+    units: Option<UnitCounts>,
+    /// Modification time of the deps directory after the build, which changes
+    /// when a concurrent build adds or removes files in it
+    deps_modified: Option<SystemTime>,
+    build_time: Duration,
+    scan_time: Duration,
+}
+
+/// Difference between the measured and estimated reclaimed size `--measure-delta`
+/// tolerates, relative to the larger of them.
+const DELTA_TOLERANCE: f64 = 0.1;
+
+/// Incremental sessions kept for each unit without `--keep-incremental` or
+/// `keep_last` in its package metadata.
+const DEFAULT_KEEP_INCREMENTAL: usize = 1;
+
+/// Find what to remove, measuring the sizes of directories if `measure`.
+fn plan(args: &Args, measure: bool) -> Result<Plan> {
+    let mut plan = find_outdated(args, measure)?;
+    plan.protect(&args.protect);
+    Ok(plan)
+}
+
+fn find_outdated(args: &Args, measure: bool) -> Result<Plan> {
+    let started = Instant::now();
+    // Sizes of directories take a walk through each of them
+    let mut size_counter = if measure {
+        SizeCounter::new(args.apparent_size)
+    } else {
+        SizeCounter::skipping()
+    };
+    let workspace = workspace(args)?;
+    let profile_path = workspace.profile_path(args);
+    if args.prune_removed {
+        let live_crates = prune::live_crates(args)?;
+        let deps_modified = directory_modified(&profile_path.join("deps"));
+        let build_time = started.elapsed();
+        let scan_started = Instant::now();
+        let scan = scan_artifacts(args, &profile_path)?;
+        let files = scan
+            .artifacts
+            .into_iter()
+            .filter(|artifact| {
+                !artifact.is_of(&live_crates) && !artifact.is_of(&workspace.policy.never_gc)
+            })
+            .collect();
+        // Crates that are never GC'ed are always in the workspace, thus live
+        let removed_crates =
+            prune::removed_directories(&profile_path, &live_crates, &mut size_counter)?;
+        return Ok(Plan {
+            target_directory: workspace.target_directory,
+            profile_path,
+            files,
+            incremental: IncrementalAnalysis::default(),
+            // Covered by the removed crates
+            unit_dirs: vec![],
+            removed_crates,
+            unreadable: scan.unreadable,
+            units: None,
+            deps_modified,
+            build_time,
+            scan_time: scan_started.elapsed(),
+        });
+    }
+    if args.other_rustc {
+        let deps_modified = directory_modified(&profile_path.join("deps"));
+        let build_time = started.elapsed();
+        let scan_started = Instant::now();
+        let others = rustc::other_rustc_figureprints(&profile_path)?;
+        let scan = scan_artifacts(args, &profile_path)?;
+        let files = scan
+            .artifacts
+            .into_iter()
+            .filter(|artifact| {
+                others.contains(&artifact.figureprint)
+                    && !artifact.is_of(&workspace.policy.never_gc)
+            })
+            .collect();
+        let unit_dirs = rustc::unit_directories(
+            &profile_path,
+            &others,
+            &|name| workspace.policy.never_gc.contains(name),
+            &mut size_counter,
+        )?;
+        return Ok(Plan {
+            target_directory: workspace.target_directory,
+            profile_path,
+            files,
+            incremental: IncrementalAnalysis::default(),
+            unit_dirs,
+            removed_crates: vec![],
+            unreadable: scan.unreadable,
+            units: None,
+            deps_modified,
+            build_time,
+            scan_time: scan_started.elapsed(),
+        });
+    }
+    let (figureprints, units) =
+        if args.gc_deps && !args.quick && !args.newest_only && args.retain.is_none() {
+            let in_use = || -> Result<(Figureprints, UnitCounts)> {
+                let (collection, _) = build(args, &workspace, &[])?;
+                let mut figureprints = collection.deps_figureprints;
+                if args.host {
+                    // Build scripts and proc macros of the build for the target are
+                    // in the host directory as well
+                    let target_args = Args {
+                        host: false,
+                        ..args.clone()
+                    };
+                    figureprints.extend(get_figureprints(&target_args, &workspace)?);
+                }
+                Ok((figureprints, collection.units))
+            };
+            match in_use() {
+            Ok((figureprints, units)) => (Some(figureprints), Some(units)),
+            Err(e) if args.fallback_newest_only => {
+                if !args.quiet {
+                    eprintln!("warning: {e:#}");
+                    eprintln!("warning: falling back to keeping the newest build of each crate");
+                }
+                (None, None)
+            }
+            Err(e) => return Err(e.context(
+                "failed to find the artifacts in use, pass --newest-only to GC without building",
+            )),
+        }
+        } else {
+            (None, None)
+        };
+    let deps_modified = directory_modified(&profile_path.join("deps"));
+    let build_time = started.elapsed();
+
+    let scan_started = Instant::now();
+    let scan = if args.gc_deps {
+        scan_artifacts(args, &profile_path)?
+    } else {
+        deps::Scan::default()
+    };
+    let mut unreadable = scan.unreadable;
+    let artifacts = scan.artifacts;
+    let mut files = match &figureprints {
+        Some(figureprints) => deps::outdated(artifacts, figureprints),
+        None => {
+            let estimation = match args.retain {
+                Some(retain) => deps::retained_figureprints(&artifacts, retain),
+                None => deps::newest_figureprints(&artifacts),
+            };
+            deps::outdated(artifacts, &estimation)
+        }
+    };
+    files.retain(|artifact| {
+        !artifact.is_of(&workspace.policy.never_gc)
+            && workspace
+                .changed_crates
+                .as_ref()
+                .is_none_or(|crates| artifact.is_of(crates))
+    });
+    // With `-p`, crates outside the selected packages aren't built, their
+    // artifacts may still be in use by the other packages
+    let built_crates = figureprints
+        .as_ref()
+        .filter(|_| !args.cargo_packages().is_empty())
+        .map(|figureprints| {
+            figureprints
+                .iter()
+                .map(|(name, _)| name.strip_prefix("lib").unwrap_or(name).to_string())
+                .collect::<HashSet<_>>()
+        });
+    if let Some(built_crates) = &built_crates {
+        files.retain(|artifact| artifact.is_of(built_crates));
+    }
+    if args.protect_binaries {
+        // Keep the `.d` files of the executables as well
+        let protected = files
+            .iter()
+            .filter(|artifact| artifact.is_executable_of(&workspace.policy.binaries))
+            .map(|artifact| artifact.figureprint.clone())
+            .collect::<HashSet<_>>();
+        files.retain(|artifact| !protected.contains(&artifact.figureprint));
+    }
+    if let Some(figureprints) = figureprints.as_ref().filter(|_| !args.remove_test_binaries) {
+        unreadable += deps::retain_newest_executables(&profile_path, &mut files, figureprints)?;
+    }
+    for (outdated, kept) in deps::retain_unshared(&profile_path, &mut files) {
+        if args.verbose {
+            eprintln!("keeping {:?}, hardlinked to {:?}", outdated, kept);
+        }
+    }
+    // Only a real build tells which build scripts are in use
+    let (unit_dirs, unreadable_units) = deps::unit_directories(
+        &profile_path,
+        &files,
+        figureprints.as_ref(),
+        &|name| {
+            workspace.policy.never_gc.contains(name)
+                || workspace
+                    .changed_crates
+                    .as_ref()
+                    .is_some_and(|crates| !crates.contains(name))
+                || built_crates
+                    .as_ref()
+                    .is_some_and(|crates| !crates.contains(name))
+        },
+        &mut size_counter,
+    )?;
+    unreadable += unreadable_units;
+
+    let incremental_path = profile_path.join("incremental");
+    let incremental = if args.gc_incremental && incremental_path.is_dir() {
+        // Sessions to keep for each unit of crate `name`, all of them for crates
+        // out of the GC's scope
+        let keep = |name: &str| {
+            if workspace
+                .changed_crates
+                .as_ref()
+                .is_some_and(|crates| !crates.contains(name))
+            {
+                return usize::MAX;
+            }
+            workspace
+                .policy
+                .keep_incremental(name, args.keep_incremental)
+                .unwrap_or(DEFAULT_KEEP_INCREMENTAL)
+        };
+        incremental::analyze(
+            &OsFileSystem,
+            &incremental_path,
+            &keep,
+            args.max_age_incremental,
+            &mut size_counter,
+            args.keep_going,
+        )?
+    } else {
+        IncrementalAnalysis::default()
+    };
+    let scan_time = scan_started.elapsed();
+
+    Ok(Plan {
+        target_directory: workspace.target_directory,
+        profile_path,
+        files,
+        incremental,
+        unit_dirs,
+        removed_crates: vec![],
+        unreadable,
+        units,
+        deps_modified,
+        build_time,
+        scan_time,
+    })
+}
+
+impl Plan {
+    /// Keep what matches any of the patterns relative to the profile directory,
+    /// with the other files and the `.fingerprint` directory of its figureprint.
+    fn protect(&mut self, patterns: &[Pattern]) {
+        if patterns.is_empty() {
+            return;
+        }
+        let profile_path = self.profile_path.clone();
+        let is_protected = |path: &Path| {
+            let relative = path.strip_prefix(&profile_path).unwrap_or(path);
+            patterns
+                .iter()
+                .any(|pattern| pattern.matches_path(relative))
+        };
+        let protected = self
+            .files
+            .iter()
+            .filter(|artifact| is_protected(&artifact.path))
+            .map(|artifact| artifact.figureprint.clone())
+            .collect::<HashSet<_>>();
+        self.files
+            .retain(|artifact| !protected.contains(&artifact.figureprint));
+        self.unit_dirs.retain(|(path, _)| {
+            let figureprint = path
+                .file_name()
+                .and_then(|name| extract_figureprint(&name.to_string_lossy()));
+            !is_protected(path)
+                && figureprint.is_none_or(|(_, figureprint)| !protected.contains(&figureprint))
+        });
+        self.incremental
+            .outdated
+            .retain(|(path, _)| !is_protected(path));
+        self.removed_crates.retain(|(path, _)| !is_protected(path));
+    }
+}
+
+/// Entries removed from all profiles.
+#[derive(Default)]
+struct Totals {
+    removed: usize,
+    size: u64,
+    /// Size that would be removed in dry run
+    reclaimable: u64,
+    outcomes: Vec<Outcome>,
+    /// Plans of dry runs with `--format json`, printed together
+    dry_runs: Vec<DryRun>,
+}
+
+/// What a dry run would remove from a profile.
+#[derive(Serialize)]
+struct DryRun {
+    profile_path: PathBuf,
+    reclaimable_bytes: u64,
+    categories: Vec<Category>,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct Category {
+    name: &'static str,
+    count: usize,
+    bytes: u64,
+}
+
+/// A file or directory to remove.
+#[derive(Serialize)]
+struct Entry {
+    path: PathBuf,
+    category: &'static str,
+    bytes: u64,
+}
+
+/// What GC'ing a profile did, written to `--output` and `--metrics`.
+#[derive(Serialize)]
+struct Outcome {
+    profile_path: PathBuf,
+    removed_files: usize,
+    removed_directories: usize,
+    reclaimed_bytes: u64,
+    failed: usize,
+    /// Entries that couldn't be read while looking for what to remove
+    unreadable: usize,
+    skipped: usize,
+    /// Units of the build telling what's in use, if gc ran one
+    units: Option<UnitCounts>,
+    /// The summary line printed for the profile
+    #[serde(skip)]
+    summary: String,
+}
+
+/// GC each profile in turn, the exit code is the first failing one.
+fn gc_profiles(args: &Args) -> Result<ExitCode> {
+    let mut totals = Totals::default();
+    let mut exit_code = ExitCode::SUCCESS;
+    let host_passes =
+        if args.include_host && build_target::build_target(&args.cargo_args)?.is_some() {
+            vec![false, true]
+        } else {
+            vec![false]
+        };
+    for profile in &args.profiles {
+        for &host in &host_passes {
+            let args = Args {
+                profile: profile.clone(),
+                host,
+                ..args.clone()
+            };
+            // The profile is reported as failed, the next ones are still GC'ed
+            let code = continue_on_error(args.keep_going, gc(&args, &mut totals))?
+                .unwrap_or(ExitCode::FAILURE);
+            if exit_code == ExitCode::SUCCESS {
+                exit_code = code;
+            }
+        }
+    }
+    let passes = args.profiles.len() * host_passes.len();
+    if passes > 1 && !args.dry_run {
+        report!(
+            args,
+            "{} {} entries from {} profile directories, {} total",
+            style("Removed").green().bold(),
+            totals.removed,
+            passes,
+            style(humansize::format_size(totals.size, DECIMAL)).bold(),
+        );
+    }
+    if args.format == Format::Json && args.check.is_none() && args.output_plan.is_none() {
+        let json = if args.dry_run {
+            serde_json::to_string_pretty(&totals.dry_runs)
+                .context("failed to serialize the dry run")?
+        } else {
+            serde_json::to_string_pretty(&totals.outcomes)
+                .context("failed to serialize the summary")?
+        };
+        println!("{}", json);
+    }
+    if let Some(path) = &args.output {
+        write_outcomes(args, path, &totals)?;
+    }
+    if let Some(path) = &args.metrics {
+        metrics::write(path, &totals.outcomes, args.apparent_size)?;
+    }
+    if args.all {
+        let cache = cache::run(args, &[], true)?;
+        let target = if args.dry_run {
+            totals.reclaimable
+        } else {
+            totals.size
+        };
+        print_all_summary(args, target, &cache);
+    }
+    Ok(exit_code)
+}
+
+/// Summary of `--all`, by what's cleaned up.
+fn print_all_summary(args: &Args, target: u64, cache: &CacheReport) {
+    let heading = if args.dry_run {
+        "Reclaimable in total:"
+    } else {
+        "Reclaimed in total:"
+    };
+    report!(args, "{}", style(heading).green().bold());
+    for (category, size) in [
+        ("target directory", target),
+        ("registry caches", cache.registry),
+        ("git checkouts", cache.git),
+    ] {
+        report!(
+            args,
+            "  {:<16}  {}",
+            category,
+            humansize::format_size(size, DECIMAL)
+        );
+    }
+    report!(
+        args,
+        "  {:<16}  {}",
+        "total",
+        style(humansize::format_size(
+            target + cache.registry + cache.git,
+            DECIMAL
+        ))
+        .bold()
+    );
+}
+
+/// Write the summary of each profile GC'ed to a file, in `--format`.
+fn write_outcomes(args: &Args, path: &Path, totals: &Totals) -> Result<()> {
+    let content = match args.format {
+        Format::Text => totals
+            .outcomes
+            .iter()
+            .map(|outcome| format!("{}\n", outcome.summary))
+            .collect(),
+        Format::Json => serde_json::to_string_pretty(&totals.outcomes)
+            .context("failed to serialize the summary")?,
+    };
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory {:?}", parent))?;
+    }
+    fs::write(path, content).with_context(|| format!("failed to write summary to {:?}", path))
+}
+
+fn gc(args: &Args, totals: &mut Totals) -> Result<ExitCode> {
+    // Anything modified after this point is written by a concurrent build
+    let run_start = SystemTime::now();
+    let started = Instant::now();
+    let (plan, changed) = match &args.apply_plan {
+        Some(path) => {
+            // Apply to the current workspace if any, the plan may be written
+            // for a copy of its target directory elsewhere
+            let target_directory = if args.manifest_path.is_some() || is_inside_project()? {
+                Some(workspace(args)?.target_directory)
+            } else {
+                None
+            };
+            saved_plan::load(path, target_directory.as_deref(), args.quiet)?
+        }
+        None => (plan(args, true)?, 0),
+    };
+    if let Some(path) = &args.output_plan {
+        saved_plan::write(&plan, path, args.apparent_size)?;
+    }
+    let Plan {
+        profile_path,
+        files: files_to_remove,
+        incremental,
+        unit_dirs,
+        removed_crates,
+        unreadable,
+        units,
+        deps_modified,
+        build_time,
+        scan_time,
+        ..
+    } = plan;
+    if !profile_path.is_dir() {
+        report!(args, "no build artifacts found in {:?}", profile_path);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.gc_deps && !args.quiet {
+        let estimate_note = if args.quick || args.newest_only || args.retain.is_some() {
+            " (estimated by modification time)"
+        } else {
+            ""
+        };
+        eprintln!(
+            "found {} outdated files, {} reclaimable{}",
+            files_to_remove.len(),
+            humansize::format_size(
+                files_to_remove
+                    .iter()
+                    .map(|artifact| artifact.size)
+                    .sum::<u64>(),
+                DECIMAL
+            ),
+            estimate_note,
+        );
+        if !unit_dirs.is_empty() {
+            eprintln!(
+                "found {} .fingerprint and build directories of them, {} reclaimable",
+                unit_dirs.len(),
+                humansize::format_size(
+                    unit_dirs.iter().map(|(_, size)| size).sum::<u64>(),
+                    DECIMAL
+                ),
+            );
+        }
+    }
+    if args.prune_removed && !args.quiet {
+        eprintln!(
+            "found {} directories of crates not in Cargo.lock, {} reclaimable",
+            removed_crates.len(),
+            humansize::format_size(
+                removed_crates.iter().map(|(_, size)| size).sum::<u64>(),
+                DECIMAL
+            ),
+        );
+    } else if args.gc_incremental && !args.quiet {
+        eprintln!(
+            "found {} outdated incremental directories of {} crates, {} reclaimable",
+            incremental.outdated.len(),
+            incremental.crates,
+            humansize::format_size(incremental.reclaimable_size(), DECIMAL),
+        );
+    }
+    if args.verbose && args.gc_deps {
+        print_to_remove(
+            "files to remove",
+            files_to_remove
+                .iter()
+                .map(|artifact| (&artifact.path, artifact.size)),
+            args.size_sort,
+        );
+        print_to_remove(
+            ".fingerprint and build directories to remove",
+            unit_dirs.iter().map(|(path, size)| (path, *size)),
+            args.size_sort,
+        );
+    }
+    if args.verbose && args.prune_removed {
+        print_to_remove(
+            "directories to remove",
+            removed_crates.iter().map(|(path, size)| (path, *size)),
+            args.size_sort,
+        );
+    }
+    if args.verbose && args.gc_incremental && !args.prune_removed {
+        print_to_remove(
+            "incremental directories to remove",
+            incremental
+                .outdated
+                .iter()
+                .map(|(path, size)| (path, *size)),
+            args.size_sort,
+        );
+    }
+    let reclaimable_size = files_to_remove
+        .iter()
+        .map(|artifact| artifact.size)
+        .sum::<u64>()
+        + unit_dirs.iter().map(|(_, size)| size).sum::<u64>()
+        + incremental.reclaimable_size()
+        + removed_crates.iter().map(|(_, size)| size).sum::<u64>();
+    if let Some(max_size) = args.check {
+        report!(
+            args,
+            "{} reclaimable, {} allowed",
+            humansize::format_size(reclaimable_size, DECIMAL),
+            humansize::format_size(max_size, DECIMAL),
+        );
+        if reclaimable_size > max_size {
+            eprintln!("run `cargo gc` to clean up");
+            return Ok(ExitCode::from(exit_code::CHECK_FAILED));
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(path) = &args.output_plan {
+        report!(
+            args,
+            "plan written to {:?}, run `cargo gc --apply-plan` to remove them",
+            path
+        );
+        if !args.quiet {
+            eprintln!("Finished in {:.1}s", started.elapsed().as_secs_f64());
+        }
+        return Ok(ExitCode::SUCCESS);
     }
     if args.dry_run {
-        println!("abort due to dry run");
-        return Ok(());
+        let directory_category = if args.prune_removed {
+            "directories of removed crates"
+        } else {
+            "incremental directories"
+        };
+        let entries = files_to_remove
+            .iter()
+            .map(|artifact| {
+                let category = if artifact.is_dep_info {
+                    ".d files"
+                } else {
+                    "deps artifacts"
+                };
+                (category, &artifact.path, artifact.size)
+            })
+            .chain(unit_dirs.iter().map(|(path, size)| {
+                let is_build = path
+                    .parent()
+                    .and_then(Path::file_name)
+                    .is_some_and(|name| name == "build");
+                let category = if is_build {
+                    "build script directories"
+                } else {
+                    ".fingerprint directories"
+                };
+                (category, path, *size)
+            }))
+            .chain(
+                incremental
+                    .outdated
+                    .iter()
+                    .chain(&removed_crates)
+                    .map(|(path, size)| (directory_category, path, *size)),
+            )
+            .collect::<Vec<_>>();
+        let categories = [
+            "deps artifacts",
+            ".d files",
+            "build script directories",
+            ".fingerprint directories",
+            directory_category,
+        ]
+        .map(|category| {
+            let (count, size) = entries
+                .iter()
+                .filter(|(entry_category, _, _)| *entry_category == category)
+                .fold((0, 0), |(count, size), (_, _, entry_size)| {
+                    (count + 1, size + entry_size)
+                });
+            (category, count, size)
+        });
+        let reclaimable = categories.iter().map(|(_, _, size)| size).sum::<u64>();
+        match args.format {
+            Format::Text if args.quiet => {}
+            Format::Text => print_categories(&profile_path, &categories),
+            Format::Json => totals.dry_runs.push(DryRun {
+                profile_path: profile_path.clone(),
+                reclaimable_bytes: reclaimable,
+                categories: categories
+                    .iter()
+                    .map(|(name, count, bytes)| Category {
+                        name,
+                        count: *count,
+                        bytes: *bytes,
+                    })
+                    .collect(),
+                entries: entries
+                    .iter()
+                    .map(|(category, path, bytes)| Entry {
+                        path: path.to_path_buf(),
+                        category,
+                        bytes: *bytes,
+                    })
+                    .collect(),
+            }),
+        }
+        totals.reclaimable += reclaimable;
+        if !args.quiet {
+            eprintln!("abort due to dry run");
+            eprintln!("Finished in {:.1}s", started.elapsed().as_secs_f64());
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let confirm_over = args.confirm_over.filter(|_| !args.yes);
+    if !confirm_removal(&profile_path, reclaimable_size, confirm_over, ask_terminal)? {
+        report!(args, "nothing removed from {:?}", profile_path);
+        return Ok(ExitCode::SUCCESS);
     }
 
+    // Hold the build lock so a concurrent cargo won't write into the files we're removing
+    let lock = lock_build_directory(&profile_path)?;
+    // Files added by a build that finished before we took the lock may be
+    // planned for removal, don't trust the plan then
+    ensure_unchanged(&profile_path.join("deps"), deps_modified)?;
+    let removal_started = Instant::now();
+    let size_before = args
+        .measure_delta
+        .then(|| dir_size(&profile_path, &mut SizeCounter::new(args.apparent_size)));
+
     // Remove old files
+    let mut failures = Failures::default();
     let mut failed = 0;
+    let mut failed_dirs = 0;
     let total_count = files_to_remove.len();
+    let mut skipped = 0;
     let mut success_size = 0;
-    for file in files_to_remove {
-        let size = fs::metadata(&file).map(|m| m.len()).unwrap_or_default();
+    let mut size_counter = SizeCounter::new(args.apparent_size);
+    let mut reclaimed_by_crate: HashMap<String, u64> = HashMap::new();
+    let open_files = if args.skip_in_use {
+        in_use::open_files()
+    } else {
+        HashSet::new()
+    };
+    // Figureprints rebuilt by a concurrent build, in use by a running process
+    // or unreadable, with the file that tells. All their files and their
+    // fingerprints are kept, a unit is removed as a whole or not at all.
+    let mut rebuilt = HashMap::new();
+    for artifact in &files_to_remove {
+        let reason = match fs::metadata(&artifact.path) {
+            Ok(metadata)
+                if metadata
+                    .modified()
+                    .is_ok_and(|modified| modified > run_start) =>
+            {
+                "newer than run start"
+            }
+            Ok(metadata) if inode(&metadata).is_some_and(|inode| open_files.contains(&inode)) => {
+                "in use by a running process"
+            }
+            Err(e) if e.kind() != io::ErrorKind::NotFound => "unreadable",
+            _ => continue,
+        };
+        rebuilt
+            .entry(artifact.figureprint.clone())
+            .or_insert((artifact.path.clone(), reason));
+    }
+    for artifact in files_to_remove {
+        let file = &artifact.path;
+        if let Some((pinned_by, reason)) = rebuilt.get(&artifact.figureprint) {
+            skipped += 1;
+            if !args.quiet && pinned_by == file {
+                eprintln!("skipped {:?} ({})", file, reason);
+            } else if !args.quiet {
+                eprintln!("skipped {:?} (kept with {:?})", file, pinned_by);
+            }
+            continue;
+        }
+        let metadata = fs::metadata(file).ok();
+        let mut size = metadata.map(|m| size_counter.count(&m)).unwrap_or_default();
         success_size += size;
-        if let Err(e) = fs::remove_file(file) {
+        if let Err(e) = remove_file(&OsFileSystem, file, args.force) {
+            success_size -= size;
+            if args.skip_in_use && in_use::is_in_use_error(&e) {
+                skipped += 1;
+                if !args.quiet {
+                    eprintln!("skipped {:?} (in use by a running process)", file);
+                }
+                rebuilt.insert(
+                    artifact.figureprint,
+                    (file.clone(), "in use by a running process"),
+                );
+                continue;
+            }
             failed += 1;
+            failures.add(file, e, args.verbose);
+        } else {
+            // Debug info of macOS binaries is a `<file>.dSYM` bundle next to it,
+            // other forms like `.pdb` and `.dwp` are files sharing the stem
+            let bundle = debug_info_bundle(file);
+            if bundle.is_dir() {
+                let bundle_size = dir_size(&bundle, &mut size_counter);
+                match remove_dir(&OsFileSystem, &bundle, args.force) {
+                    Ok(()) => {
+                        success_size += bundle_size;
+                        size += bundle_size;
+                    }
+                    Err(e) => failures.add(&bundle, e, args.verbose),
+                }
+            }
+            *reclaimed_by_crate
+                .entry(artifact.crate_name().to_string())
+                .or_default() += size;
+        }
+    }
+
+    // Remove fingerprints of the removed files and outdated build scripts,
+    // cargo rebuilds them if needed
+    let mut failed_unit_dirs = 0;
+    let mut skipped_unit_dirs = 0;
+    let unit_dirs = unit_dirs
+        .into_iter()
+        .filter(|(dir, _)| {
+            let is_rebuilt = dir
+                .file_name()
+                .and_then(|name| extract_figureprint(&name.to_string_lossy()))
+                .is_some_and(|(_, figureprint)| rebuilt.contains_key(&figureprint));
+            if is_rebuilt {
+                skipped_unit_dirs += 1;
+            }
+            !is_rebuilt
+        })
+        .collect::<Vec<_>>();
+    let total_unit_dir_count = unit_dirs.len();
+    for (dir, size) in unit_dirs {
+        success_size += size;
+        if let Err(e) = remove_dir(&OsFileSystem, &dir, args.force) {
+            failed_unit_dirs += 1;
             success_size -= size;
-            println!("failed to remove file: {}", e);
-        };
+            failures.add(&dir, e, args.verbose);
+        }
     }
 
-    let fail_report = if failed == 0 {
+    // Remove old incremental directories, or all directories of removed crates
+    let unreadable = unreadable + incremental.unreadable;
+    let total_dir_count = incremental.outdated.len() + removed_crates.len();
+    for (dir, size) in incremental.outdated.into_iter().chain(removed_crates) {
+        success_size += size;
+        if let Err(e) = remove_dir(&OsFileSystem, &dir, args.force) {
+            failed_dirs += 1;
+            success_size -= size;
+            failures.add(&dir, e, args.verbose);
+        }
+    }
+
+    // Clean up directories emptied by the removal, but keep the top-level ones
+    // like `deps` and `.fingerprint`
+    let empty_dirs = continue_on_error(
+        args.keep_going,
+        remove_empty_profile_directories(&profile_path),
+    )?
+    .unwrap_or_default();
+
+    let size_after = args
+        .measure_delta
+        .then(|| dir_size(&profile_path, &mut SizeCounter::new(args.apparent_size)));
+
+    let dedup = if args.dedup {
+        continue_on_error(
+            args.keep_going,
+            dedup::run(&profile_path.join("deps"), args.apparent_size),
+        )?
+        .unwrap_or_default()
+    } else {
+        DedupReport::default()
+    };
+
+    drop(lock);
+    let removal_time = removal_started.elapsed();
+
+    let total_failed = failed + failed_unit_dirs + failed_dirs;
+    // Failing to read is told apart from failing to remove what's read
+    let fail_report = match (total_failed, unreadable) {
+        (0, 0) => "".to_string(),
+        (failed, 0) => format!(", {} failed to remove", failed),
+        (0, unreadable) => format!(", {} unreadable", unreadable),
+        (failed, unreadable) => format!(", {} failed to remove, {} unreadable", failed, unreadable),
+    };
+    let total_skipped = skipped + skipped_unit_dirs + changed;
+    let skip_report = if total_skipped == 0 {
         "".to_string()
     } else {
-        format!(", {} files failed to remove", failed)
+        format!(", {} skipped", total_skipped)
     };
-    println!(
-        "Removed {} files from {:?}, {} total{}",
-        total_count - failed,
+    let removed_files = total_count - failed - skipped;
+    let removed_dirs = total_dir_count - failed_dirs;
+    let mut removed = vec![];
+    if args.gc_deps {
+        removed.push(format!("{} files", removed_files));
+    }
+    if total_unit_dir_count > 0 {
+        removed.push(format!(
+            "{} .fingerprint and build directories",
+            total_unit_dir_count - failed_unit_dirs
+        ));
+    }
+    if args.prune_removed {
+        removed.push(format!("{} directories", removed_dirs));
+    } else if args.gc_incremental {
+        removed.push(format!("{} incremental directories", removed_dirs));
+    }
+    report!(
+        args,
+        "{} {} from {:?}, {} total{}{}",
+        style("Removed").green().bold(),
+        removed.join(" and "),
         profile_path,
-        humansize::format_size(success_size, DECIMAL),
+        style(humansize::format_size(success_size, DECIMAL)).bold(),
         fail_report,
+        skip_report,
     );
-    Ok(())
+    totals.outcomes.push(Outcome {
+        profile_path: profile_path.clone(),
+        removed_files,
+        removed_directories: total_unit_dir_count - failed_unit_dirs + removed_dirs,
+        reclaimed_bytes: success_size,
+        failed: total_failed,
+        unreadable,
+        skipped: total_skipped,
+        units,
+        summary: format!(
+            "Removed {} from {:?}, {} total{}{}",
+            removed.join(" and "),
+            profile_path,
+            humansize::format_size(success_size, DECIMAL),
+            fail_report,
+            skip_report,
+        ),
+    });
+    if empty_dirs > 0 {
+        report!(args, "Removed {} empty directories", empty_dirs);
+    }
+    if args.dedup {
+        report!(
+            args,
+            "Replaced {} duplicate files by hardlinks, saved {}",
+            dedup.linked,
+            humansize::format_size(dedup.saved_size, DECIMAL),
+        );
+    }
+    if let (Some(before), Some(after)) = (size_before, size_after) {
+        let measured = before.saturating_sub(after);
+        report!(
+            args,
+            "Measured {} reclaimed in {:?}, {} estimated",
+            humansize::format_size(measured, DECIMAL),
+            profile_path,
+            humansize::format_size(success_size, DECIMAL),
+        );
+        if measured.abs_diff(success_size) as f64
+            > success_size.max(measured) as f64 * DELTA_TOLERANCE
+        {
+            eprintln!(
+                "{}: the measured size differs from the estimate, files hardlinked outside the removed ones free no space",
+                style("warning").yellow().bold().for_stderr(),
+            );
+        }
+    }
+    failures.report(args.force);
+    if args.verbose && !reclaimed_by_crate.is_empty() {
+        let mut crates = reclaimed_by_crate.into_iter().collect::<Vec<_>>();
+        crates.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        report!(args, "largest crates by reclaimed size:");
+        for (name, size) in crates.into_iter().take(stats::LARGEST_CRATES) {
+            report!(
+                args,
+                "  {}: {}",
+                name,
+                humansize::format_size(size, DECIMAL)
+            );
+        }
+    }
+    if args.verbose {
+        eprintln!(
+            "build {:.1}s, scan {:.1}s, removed {} entries in {:.1}s ({})",
+            build_time.as_secs_f64(),
+            scan_time.as_secs_f64(),
+            removed_files + removed_dirs,
+            removal_time.as_secs_f64(),
+            humansize::format_size(success_size, DECIMAL),
+        );
+    }
+    if !args.quiet {
+        eprintln!("Finished in {:.1}s", started.elapsed().as_secs_f64());
+    }
+    totals.removed += removed_files + (total_unit_dir_count - failed_unit_dirs) + removed_dirs;
+    totals.size += success_size;
+    if total_failed > 0 {
+        return Ok(ExitCode::from(exit_code::REMOVAL_FAILED));
+    }
+    if unreadable > 0 {
+        return Ok(ExitCode::from(exit_code::ERROR));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Print (Path, Size) of entries to remove for `--verbose`, the largest first
+/// with their sizes if `size_sort`.
+fn print_to_remove<'a>(
+    heading: &str,
+    entries: impl Iterator<Item = (&'a PathBuf, u64)>,
+    size_sort: bool,
+) {
+    if !size_sort {
+        let paths = entries.map(|(path, _)| path).collect::<Vec<_>>();
+        eprintln!("{heading} {paths:#?}");
+        return;
+    }
+    let mut entries = entries.collect::<Vec<_>>();
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    eprintln!("{heading}:");
+    for (path, size) in entries {
+        eprintln!(
+            "  {:>10}  {:?}",
+            humansize::format_size(size, DECIMAL),
+            path
+        );
+    }
+}
+
+/// Print (Category, Count, Size) of entries to remove, skipping empty ones,
+/// and their total.
+fn print_categories(profile_path: &Path, categories: &[(&str, usize, u64)]) {
+    println!("Would remove from {:?}:", profile_path);
+    let width = categories
+        .iter()
+        .map(|(category, _, _)| category.len())
+        .max()
+        .unwrap_or_default();
+    for (category, count, size) in categories.iter().filter(|(_, count, _)| *count > 0) {
+        println!(
+            "  {:<width$}  {:>6}  {}",
+            category,
+            count,
+            humansize::format_size(*size, DECIMAL)
+        );
+    }
+    println!(
+        "  {:<width$}  {:>6}  {}",
+        "total",
+        categories.iter().map(|(_, count, _)| count).sum::<usize>(),
+        style(humansize::format_size(
+            categories.iter().map(|(_, _, size)| size).sum::<u64>(),
+            DECIMAL
+        ))
+        .bold()
+    );
+}
+
+fn debug_info_bundle(path: &Path) -> PathBuf {
+    let mut bundle = path.as_os_str().to_owned();
+    bundle.push(".dSYM");
+    PathBuf::from(bundle)
+}
+
+/// How many example paths are shown for each kind of failure.
+const FAILURE_EXAMPLES: usize = 3;
+
+/// Removal failures grouped by error kind.
+#[derive(Default)]
+struct Failures {
+    by_kind: HashMap<io::ErrorKind, Vec<PathBuf>>,
+}
+
+impl Failures {
+    fn add(&mut self, path: &Path, error: io::Error, verbose: bool) {
+        if verbose {
+            eprintln!("failed to remove {:?}: {}", path, error);
+        }
+        self.by_kind
+            .entry(error.kind())
+            .or_default()
+            .push(path.to_path_buf());
+    }
+
+    fn report(&self, force: bool) {
+        if self.by_kind.is_empty() {
+            return;
+        }
+        let mut by_kind = self.by_kind.iter().collect::<Vec<_>>();
+        by_kind.sort_by_key(|(_, paths)| std::cmp::Reverse(paths.len()));
+        eprintln!("{}", style("Failed to remove:").red().bold().for_stderr());
+        for (kind, paths) in by_kind {
+            let examples = paths
+                .iter()
+                .take(FAILURE_EXAMPLES)
+                .map(|path| format!("{:?}", path))
+                .collect::<Vec<_>>();
+            eprintln!("  {} {:?}, e.g. {}", paths.len(), kind, examples.join(", "));
+        }
+        if !force && self.by_kind.contains_key(&io::ErrorKind::PermissionDenied) {
+            eprintln!("read-only files can be removed with `--force`");
+        }
+    }
+}
+
+/// Remove a file. With `force`, a file that can't be removed for permission is
+/// made writable and retried once.
+fn remove_file(fs: &dyn FileSystem, path: &Path, force: bool) -> io::Result<()> {
+    let path = long_path(path);
+    match fs.remove_file(&path) {
+        Err(e) if force && e.kind() == io::ErrorKind::PermissionDenied => {
+            make_writable(&path)?;
+            fs.remove_file(&path)
+        }
+        result => result,
+    }
+}
+
+/// Remove a directory with everything in it. With `force`, a directory that
+/// can't be removed for permission has all its entries made writable and is
+/// retried once.
+fn remove_dir(fs: &dyn FileSystem, path: &Path, force: bool) -> io::Result<()> {
+    let path = long_path(path);
+    match fs.remove_dir_all(&path) {
+        Err(e) if force && e.kind() == io::ErrorKind::PermissionDenied => {
+            make_tree_writable(&path);
+            fs.remove_dir_all(&path)
+        }
+        result => result,
+    }
+}
+
+/// Make `path` and everything under it writable, ignoring failures.
+fn make_tree_writable(path: &Path) {
+    let _ = make_writable(path);
+    let Ok(entries) = fs::read_dir(path) else {
+        return;
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            make_tree_writable(&entry.path());
+        } else {
+            let _ = make_writable(&entry.path());
+        }
+    }
+}
+
+/// Paths on Windows are limited to 260 characters unless in the extended-length
+/// form `\\?\C:\...`, which target directories deep in a workspace exceed.
+#[cfg(windows)]
+fn long_path(path: &Path) -> PathBuf {
+    use std::{
+        ffi::OsString,
+        path::{Component, Prefix},
+    };
+    // Extended-length paths are not normalized, only take absolute ones on a
+    // drive like `C:\`
+    match path.components().next() {
+        Some(Component::Prefix(prefix))
+            if matches!(prefix.kind(), Prefix::Disk(_)) && path.has_root() =>
+        {
+            let mut long = OsString::from(r"\\?\");
+            long.push(path.as_os_str());
+            PathBuf::from(long)
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(not(windows))]
+fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[cfg(unix)]
+fn make_writable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o200);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn make_writable(path: &Path) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions)
+}
+
+/// Signature a `CACHEDIR.TAG` file starts with, see https://bford.info/cachedir/
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Directories in the profile directory holding a sub-directory per unit, whose
+/// contents only cargo manages.
+const UNIT_DIRS: [&str; 2] = ["build", ".fingerprint"];
+
+/// Whether `path` holds a valid `CACHEDIR.TAG`. Directories tagged by someone
+/// else inside the target directory are left alone.
+fn is_cache_dir_tagged(path: &Path) -> bool {
+    let mut signature = [0; CACHEDIR_TAG_SIGNATURE.len()];
+    File::open(path.join("CACHEDIR.TAG"))
+        .and_then(|mut file| io::Read::read_exact(&mut file, &mut signature))
+        .is_ok_and(|_| signature == CACHEDIR_TAG_SIGNATURE)
+}
+
+/// Remove empty directories in the profile directory, returns how many are
+/// removed. The top-level ones like `deps` and `.fingerprint` are kept.
+///
+/// Nothing in `build` and `.fingerprint` is touched, outdated units there are
+/// removed as a whole while the ones in use may have empty directories cargo
+/// expects, like the `OUT_DIR` of a build script that writes nothing.
+fn remove_empty_profile_directories(profile_path: &Path) -> Result<usize> {
+    let mut empty_dirs = 0;
+    for entry in fs::read_dir(profile_path)
+        .with_context(|| format!("failed to read profile directory: {:?}", profile_path))?
+    {
+        let entry = entry.with_context(|| format!("failed to read entry in {:?}", profile_path))?;
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir())
+            && !UNIT_DIRS.contains(&&*entry.file_name().to_string_lossy())
+        {
+            empty_dirs += remove_empty_directories(&entry.path());
+        }
+    }
+    Ok(empty_dirs)
+}
+
+/// Whether to remove `size` from the profile directory, asked with `ask` when
+/// it's over `confirm_over`.
+fn confirm_removal(
+    profile_path: &Path,
+    size: u64,
+    confirm_over: Option<u64>,
+    ask: impl FnOnce(&str) -> Result<String>,
+) -> Result<bool> {
+    if confirm_over.is_none_or(|threshold| size <= threshold) {
+        return Ok(true);
+    }
+    let answer = ask(&format!(
+        "Remove {} from {:?}? [y/N] ",
+        humansize::format_size(size, DECIMAL),
+        profile_path
+    ))?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Ask `question` on the terminal and read the answer.
+fn ask_terminal(question: &str) -> Result<String> {
+    let term = Term::stderr();
+    if !term.is_term() {
+        anyhow::bail!(
+            "no terminal to ask \"{}\", pass --yes to confirm",
+            question.trim_end()
+        );
+    }
+    term.write_str(question)
+        .context("failed to write to the terminal")?;
+    term.read_line().context("failed to read from the terminal")
+}
+
+/// Warn that an entry is skipped as it can't be read.
+fn warn_unreadable(path: &Path, error: impl std::fmt::Display) {
+    eprintln!(
+        "{}: skipped {:?} as it can't be read: {}",
+        style("warning").yellow().bold().for_stderr(),
+        path,
+        error
+    );
+}
+
+/// With `--keep-going`, print the error as a warning and return `None` instead
+/// of failing.
+fn continue_on_error<T>(keep_going: bool, result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if keep_going => {
+            eprintln!(
+                "{}: {}, going on",
+                style("warning").yellow().bold().for_stderr(),
+                format!("{e:#}").trim_end()
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove empty directories under `path`, returns how many are removed. `path`
+/// itself is kept.
+fn remove_empty_directories(path: &Path) -> usize {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if !entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            continue;
+        }
+        let dir = entry.path();
+        if is_cache_dir_tagged(&dir) {
+            continue;
+        }
+        removed += remove_empty_directories(&dir);
+        // Only succeeds when the directory is empty
+        if fs::remove_dir(&dir).is_ok() {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// Sums up file sizes, counting files hardlinked to each other only once.
+struct SizeCounter {
+    apparent: bool,
+    /// Whether to count at all, [`dir_size`] doesn't walk directories if not
+    measure: bool,
+    /// (Device, Inode) of counted files
+    inodes: HashSet<(u64, u64)>,
+}
+
+impl SizeCounter {
+    fn new(apparent: bool) -> Self {
+        Self {
+            apparent,
+            measure: true,
+            inodes: HashSet::new(),
+        }
+    }
+
+    /// A counter that counts nothing, for when sizes aren't needed.
+    fn skipping() -> Self {
+        Self {
+            measure: false,
+            ..Self::new(false)
+        }
+    }
+
+    /// The size `metadata` adds to the total, which is 0 if the same inode is
+    /// counted before.
+    fn count(&mut self, metadata: &Metadata) -> u64 {
+        if !self.measure {
+            return 0;
+        }
+        if let Some(inode) = inode(metadata) {
+            if !self.inodes.insert(inode) {
+                return 0;
+            }
+        }
+        file_size(metadata, self.apparent)
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(unix)]
+fn hard_links(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+/// Without inodes to tell which files are linked, every file counts as one.
+#[cfg(not(unix))]
+fn hard_links(_metadata: &Metadata) -> u64 {
+    1
+}
+
+/// The apparent size is the length of the file. Otherwise it's the space
+/// allocated on disk, which is smaller for sparse files and larger for small
+/// ones as they occupy whole blocks.
+#[cfg(unix)]
+fn file_size(metadata: &Metadata, apparent: bool) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    if apparent {
+        metadata.len()
+    } else {
+        // `blocks()` is always in 512-byte units, regardless of the block size
+        metadata.blocks() * 512
+    }
+}
+
+/// Allocated size isn't available, always use the apparent size.
+#[cfg(not(unix))]
+fn file_size(metadata: &Metadata, _apparent: bool) -> u64 {
+    metadata.len()
+}
+
+/// Total size of all files under `path`. Entries that can't be read are ignored.
+fn dir_size(path: &Path, counter: &mut SizeCounter) -> u64 {
+    if !counter.measure {
+        return 0;
+    }
+    let Ok(entries) = fs::read_dir(long_path(path)) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path(), counter),
+            Ok(metadata) => counter.count(&metadata),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_fresh_and_dirty_units_of_the_build() {
+        let json = [
+            r#"{"reason":"compiler-artifact","fresh":true,"filenames":["/t/debug/deps/libfoo-0123456789abcdef.rlib"]}"#,
+            r#"{"reason":"compiler-artifact","fresh":false,"filenames":["/t/debug/deps/bar-fedcba9876543210"]}"#,
+            r#"{"reason":"build-script-executed","out_dir":"/t/debug/build/baz-1111111111111111/out"}"#,
+            r#"{"reason":"build-finished","success":true}"#,
+        ]
+        .join("\n");
+        let collection = OutputCollection::from_json(&json).unwrap();
+        assert_eq!(collection.units, UnitCounts { fresh: 1, dirty: 1 });
+        assert_eq!(collection.deps_figureprints.len(), 3);
+    }
+
+    #[test]
+    fn keeps_build_script_units_of_the_build() {
+        let json = [
+            r#"{"reason":"compiler-artifact","target":{"kind":["custom-build"]},"filenames":["/t/debug/build/foo-0123456789abcdef/build_script_build-0123456789abcdef"]}"#,
+            r#"{"reason":"build-script-executed","out_dir":"/t/debug/build/foo-1111111111111111/out"}"#,
+        ]
+        .join("\n");
+        let collection = OutputCollection::from_json(&json).unwrap();
+        assert!(collection
+            .deps_figureprints
+            .contains(&("foo".to_string(), "0123456789abcdef".to_string())));
+        assert!(collection
+            .deps_figureprints
+            .contains(&("foo".to_string(), "1111111111111111".to_string())));
+    }
+
+    #[test]
+    fn reads_build_output_with_invalid_utf8() {
+        // A path with bytes that aren't UTF-8 between valid lines
+        let stdout = [
+            &br#"{"reason":"compiler-artifact","filenames":["/t/debug/deps/libfoo-0123456789abcdef.rlib"]}"#[..],
+            b"\n{\"reason\":\"compiler-artifact\",\"filenames\":[\"/t/\xff\xfe/deps/bar-fedcba9876543210\"]}\n",
+            br#"{"reason":"build-finished","success":true}"#,
+        ]
+        .concat();
+
+        let collection = OutputCollection::from_stdout(&stdout).unwrap();
+        assert_eq!(
+            collection.deps_figureprints,
+            HashSet::from([
+                ("libfoo".to_string(), "0123456789abcdef".to_string()),
+                ("bar".to_string(), "fedcba9876543210".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn resolves_the_target_directory_against_the_workspace_root() {
+        use crate::filesystem::MemoryFileSystem;
+
+        let fs = MemoryFileSystem::default();
+        let metadata = |target_directory: &str| -> cargo_metadata::Metadata {
+            serde_json::from_value(serde_json::json!({
+                "packages": [],
+                "workspace_members": [],
+                "resolve": null,
+                "workspace_root": "/workspace",
+                "target_directory": target_directory,
+                "version": 1,
+            }))
+            .unwrap()
+        };
+        assert_eq!(
+            target_directory(&fs, &metadata("build/target")).unwrap(),
+            Path::new("/workspace/build/target")
+        );
+        assert_eq!(
+            target_directory(&fs, &metadata("/cache/target")).unwrap(),
+            Path::new("/cache/target")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolves_a_symlinked_target_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let real_target = dir.path().join("real-target");
+        fs::create_dir(&real_target).unwrap();
+        std::os::unix::fs::symlink(&real_target, dir.path().join("target")).unwrap();
+        fs::write(dir.path().join("file"), "").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("file"), dir.path().join("file-target"))
+            .unwrap();
+        let metadata = |target_directory: &str| -> cargo_metadata::Metadata {
+            serde_json::from_value(serde_json::json!({
+                "packages": [],
+                "workspace_members": [],
+                "resolve": null,
+                "workspace_root": dir.path(),
+                "target_directory": target_directory,
+                "version": 1,
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(
+            target_directory(&OsFileSystem, &metadata("target")).unwrap(),
+            fs::canonicalize(&real_target).unwrap()
+        );
+        let error = target_directory(&OsFileSystem, &metadata("file-target")).unwrap_err();
+        assert!(
+            error.to_string().contains("which is not a directory"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn artifact_stem_strips_every_extension() {
+        assert_eq!(
+            artifact_stem(Path::new("deps/libfoo-0123456789abcdef.so.1.2.3")).as_deref(),
+            Some("libfoo-0123456789abcdef")
+        );
+        assert_eq!(
+            artifact_stem(Path::new("deps/foo-0123456789abcdef")).as_deref(),
+            Some("foo-0123456789abcdef")
+        );
+        assert_eq!(artifact_stem(Path::new("deps/.DS_Store")), None);
+    }
+
+    #[test]
+    fn groups_shared_libraries_of_every_platform() {
+        for name in [
+            "libfoo-0123456789abcdef.so",
+            "libfoo-0123456789abcdef.so.1.2.3",
+            "libfoo-0123456789abcdef.dylib",
+            "foo-0123456789abcdef.dll",
+            "foo-0123456789abcdef.dll.lib",
+            "foo-0123456789abcdef.dll.exp",
+            "foo-0123456789abcdef.pdb",
+        ] {
+            let stem = artifact_stem(&Path::new("deps").join(name)).unwrap();
+            let (crate_name, figureprint) = extract_figureprint(&stem).unwrap();
+            assert_eq!(
+                crate_name.strip_prefix("lib").unwrap_or(&crate_name),
+                "foo",
+                "{name}"
+            );
+            assert_eq!(figureprint, "0123456789abcdef", "{name}");
+        }
+    }
+
+    #[test]
+    fn extract_figureprint_requires_hash() {
+        assert_eq!(
+            extract_figureprint("libtracing-subscriber-0123456789abcdef"),
+            Some((
+                "libtracing_subscriber".to_string(),
+                "0123456789abcdef".to_string()
+            ))
+        );
+        assert_eq!(extract_figureprint("libfoo-bar"), None);
+        assert_eq!(extract_figureprint("libfoo-0123456789abcdeg"), None);
+        assert_eq!(extract_figureprint("libfoo-0123456789abcdef0"), None);
+        assert_eq!(extract_figureprint("-0123456789abcdef"), None);
+        assert_eq!(extract_figureprint("libfoo"), None);
+    }
+
+    #[test]
+    fn keeps_empty_directories_of_units() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path().join("build/foo-0123456789abcdef/out");
+        let fingerprint = dir.path().join(".fingerprint/foo-0123456789abcdef");
+        let session = dir.path().join("incremental/foo-0w2sozlfdr77a");
+        for path in [&out_dir, &fingerprint, &session] {
+            fs::create_dir_all(path).unwrap();
+        }
+
+        assert_eq!(remove_empty_profile_directories(dir.path()).unwrap(), 1);
+        assert!(out_dir.is_dir());
+        assert!(fingerprint.is_dir());
+        assert!(!session.exists());
+    }
+
+    #[test]
+    fn asks_before_removing_over_the_threshold() {
+        let profile_path = Path::new("target/debug");
+        for (answer, confirmed) in [("y\n", true), ("yes", true), ("n\n", false), ("", false)] {
+            let mut asked = None;
+            let ask = |question: &str| {
+                asked = Some(question.to_string());
+                Ok(answer.to_string())
+            };
+            assert_eq!(
+                confirm_removal(profile_path, 2_000, Some(1_000), ask).unwrap(),
+                confirmed
+            );
+            assert!(asked.unwrap().contains("Remove 2 kB"));
+        }
+    }
+
+    #[test]
+    fn removes_up_to_the_threshold_without_asking() {
+        let profile_path = Path::new("target/debug");
+        let ask = |_: &str| -> Result<String> { panic!("asked") };
+        assert!(confirm_removal(profile_path, 1_000, Some(1_000), ask).unwrap());
+        assert!(confirm_removal(profile_path, 2_000, None, ask).unwrap());
+    }
+
+    #[test]
+    fn skipping_counter_measures_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file"), "content").unwrap();
+
+        assert_eq!(dir_size(dir.path(), &mut SizeCounter::skipping()), 0);
+        assert_eq!(dir_size(dir.path(), &mut SizeCounter::new(true)), 7);
+    }
+
+    /// The OS file system, refusing to remove read-only entries like it does
+    /// for users other than root.
+    struct ReadOnlyDenied;
+
+    impl ReadOnlyDenied {
+        fn check(path: &Path) -> io::Result<()> {
+            let read_only = walk(path)
+                .into_iter()
+                .any(|path| fs::metadata(path).is_ok_and(|m| m.permissions().readonly()));
+            if read_only {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{:?} is read-only", path),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    /// `path` and everything under it.
+    fn walk(path: &Path) -> Vec<PathBuf> {
+        let mut paths = vec![path.to_path_buf()];
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                paths.extend(walk(&entry.path()));
+            }
+        }
+        paths
+    }
+
+    impl FileSystem for ReadOnlyDenied {
+        fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+            OsFileSystem.read_dir(path)
+        }
+
+        fn metadata(&self, path: &Path) -> io::Result<filesystem::EntryMetadata> {
+            OsFileSystem.metadata(path)
+        }
+
+        fn symlink_metadata(&self, path: &Path) -> io::Result<filesystem::EntryMetadata> {
+            OsFileSystem.symlink_metadata(path)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            Self::check(path)?;
+            OsFileSystem.remove_file(path)
+        }
+
+        fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+            Self::check(path)?;
+            OsFileSystem.remove_dir_all(path)
+        }
+
+        fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+            OsFileSystem.canonicalize(path)
+        }
+    }
+
+    fn make_read_only(path: &Path) {
+        let mut permissions = fs::metadata(path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(path, permissions).unwrap();
+    }
+
+    #[test]
+    fn force_removes_read_only_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("libfoo-0123456789abcdef.rlib");
+        fs::write(&file, "").unwrap();
+        make_read_only(&file);
+
+        let error = remove_file(&ReadOnlyDenied, &file, false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(file.exists());
+        remove_file(&ReadOnlyDenied, &file, true).unwrap();
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn force_removes_read_only_trees() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit = dir.path().join("foo-0123456789abcdef");
+        fs::create_dir_all(unit.join("out")).unwrap();
+        fs::write(unit.join("out/generated.rs"), "").unwrap();
+        make_read_only(&unit.join("out/generated.rs"));
+        make_read_only(&unit.join("out"));
+
+        let error = remove_dir(&ReadOnlyDenied, &unit, false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(unit.join("out/generated.rs").exists());
+        remove_dir(&ReadOnlyDenied, &unit, true).unwrap();
+        assert!(!unit.exists());
+    }
+
+    #[test]
+    fn skips_empty_directories_of_tagged_caches() {
+        let dir = tempfile::tempdir().unwrap();
+        let tagged = dir.path().join("tagged");
+        let mistagged = dir.path().join("mistagged");
+        fs::create_dir_all(tagged.join("empty")).unwrap();
+        fs::write(tagged.join("CACHEDIR.TAG"), CACHEDIR_TAG_SIGNATURE).unwrap();
+        fs::create_dir_all(mistagged.join("empty")).unwrap();
+        fs::write(mistagged.join("CACHEDIR.TAG"), "Signature: none").unwrap();
+
+        assert!(is_cache_dir_tagged(&tagged));
+        assert!(!is_cache_dir_tagged(&mistagged));
+        assert_eq!(remove_empty_directories(dir.path()), 1);
+        assert!(tagged.join("empty").exists());
+        assert!(!mistagged.join("empty").exists());
+    }
+
+    #[test]
+    fn aborts_when_deps_change_after_the_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let deps = dir.path().join("deps");
+        fs::create_dir(&deps).unwrap();
+        filetime::set_file_mtime(&deps, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+        let deps_modified = directory_modified(&deps);
+        ensure_unchanged(&deps, deps_modified).unwrap();
+
+        // A concurrent build writes a unit between the build and the removal
+        fs::write(deps.join("libfresh-0123456789abcdef.rlib"), "").unwrap();
+        let error = ensure_unchanged(&deps, deps_modified).unwrap_err();
+        assert!(error.to_string().contains("please retry"));
+        ensure_unchanged(&deps, None).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn extends_absolute_paths_on_a_drive() {
+        assert_eq!(
+            long_path(Path::new(r"C:\target\debug\deps")),
+            Path::new(r"\\?\C:\target\debug\deps")
+        );
+        // Left alone as extended-length paths aren't normalized
+        for path in [r"target\debug", r"C:target", r"\\server\share\target"] {
+            assert_eq!(long_path(Path::new(path)), Path::new(path));
+        }
+    }
+
+    #[test]
+    fn removes_trees_deeper_than_max_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit = dir.path().join("app-0123456789abcdef");
+        let deep = (0..30).fold(unit.clone(), |path, i| path.join(format!("nested-{i:02}")));
+        assert!(deep.as_os_str().len() > 260);
+        fs::create_dir_all(long_path(&deep)).unwrap();
+        fs::write(long_path(&deep.join("output.rs")), "").unwrap();
+
+        remove_dir(&OsFileSystem, &unit, false).unwrap();
+        assert!(!unit.exists());
+    }
+
+    #[test]
+    fn dir_size_sums_nested_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit = dir.path().join("app-0123456789abcdef");
+        let session = unit.join("s-abc-def/nested");
+        fs::create_dir_all(&session).unwrap();
+        fs::write(unit.join("s-abc-def.lock"), "").unwrap();
+        fs::write(unit.join("s-abc-def/query-cache.bin"), "x".repeat(100)).unwrap();
+        fs::write(session.join("dep-graph.bin"), "x".repeat(20)).unwrap();
+
+        assert_eq!(dir_size(&unit, &mut SizeCounter::new(true)), 120);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn allocated_size_differs_from_apparent_size() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let small = dir.path().join("small");
+        fs::write(&small, "0123456789").unwrap();
+        let metadata = fs::metadata(&small).unwrap();
+        assert_eq!(file_size(&metadata, true), 10);
+        assert_eq!(file_size(&metadata, false), metadata.blocks() * 512);
+
+        // Sparse, nothing is written
+        let sparse = dir.path().join("sparse");
+        File::create(&sparse)
+            .unwrap()
+            .set_len(16 * 1024 * 1024)
+            .unwrap();
+        let metadata = fs::metadata(&sparse).unwrap();
+        assert_eq!(file_size(&metadata, true), 16 * 1024 * 1024);
+        assert!(file_size(&metadata, false) < 16 * 1024 * 1024);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn counter_counts_each_inode_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("libfoo-0123456789abcdef.rlib");
+        let link = dir.path().join("libfoo-fedcba9876543210.rlib");
+        fs::write(&file, "x".repeat(100)).unwrap();
+        fs::hard_link(&file, &link).unwrap();
+
+        let mut counter = SizeCounter::new(true);
+        assert_eq!(counter.count(&fs::metadata(&file).unwrap()), 100);
+        assert_eq!(counter.count(&fs::metadata(&link).unwrap()), 0);
+        // A new counter, like the next run, counts it again
+        assert_eq!(
+            SizeCounter::new(true).count(&fs::metadata(&link).unwrap()),
+            100
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dir_size_counts_hardlinks_once() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        fs::write(dir.path().join("file"), "x".repeat(100)).unwrap();
+        fs::hard_link(dir.path().join("file"), dir.path().join("nested/link")).unwrap();
+
+        let mut counter = SizeCounter::new(true);
+        assert_eq!(dir_size(dir.path(), &mut counter), 100);
+        // Counted once across directories measured by the same counter
+        fs::create_dir(dir.path().join("other")).unwrap();
+        fs::hard_link(dir.path().join("file"), dir.path().join("other/link")).unwrap();
+        assert_eq!(dir_size(&dir.path().join("other"), &mut counter), 0);
+    }
 }
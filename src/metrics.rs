@@ -0,0 +1,173 @@
+use std::{
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{dir_size, Outcome, SizeCounter, UnitCounts};
+
+/// Write the outcome of each profile as Prometheus gauges, for the textfile
+/// collector of node_exporter. The file is replaced at once so the collector
+/// never reads it half written. Units are only counted when gc ran a build,
+/// profiles without one have no sample of them.
+pub fn write(path: &Path, outcomes: &[Outcome], apparent_size: bool) -> Result<()> {
+    let values = |value: fn(&Outcome) -> u64| {
+        outcomes
+            .iter()
+            .map(|outcome| Some(value(outcome)))
+            .collect::<Vec<_>>()
+    };
+    let units = |value: fn(&UnitCounts) -> usize| {
+        outcomes
+            .iter()
+            .map(|outcome| outcome.units.as_ref().map(|units| value(units) as u64))
+            .collect::<Vec<_>>()
+    };
+    let gauges = [
+        (
+            "cargo_gc_reclaimed_bytes",
+            "Bytes reclaimed by the last run",
+            values(|outcome| outcome.reclaimed_bytes),
+        ),
+        (
+            "cargo_gc_removed_files",
+            "Files removed by the last run",
+            values(|outcome| outcome.removed_files as u64),
+        ),
+        (
+            "cargo_gc_failed_entries",
+            "Entries the last run failed to remove",
+            values(|outcome| outcome.failed as u64),
+        ),
+        (
+            "cargo_gc_unreadable_entries",
+            "Entries the last run failed to read",
+            values(|outcome| outcome.unreadable as u64),
+        ),
+        (
+            "cargo_gc_fresh_units",
+            "Units the build of the last run found up to date",
+            units(|units| units.fresh),
+        ),
+        (
+            "cargo_gc_dirty_units",
+            "Units the build of the last run compiled",
+            units(|units| units.dirty),
+        ),
+        (
+            "cargo_gc_target_size_bytes",
+            "Size of the profile directory after the last run",
+            outcomes
+                .iter()
+                .map(|outcome| {
+                    Some(dir_size(
+                        &outcome.profile_path,
+                        &mut SizeCounter::new(apparent_size),
+                    ))
+                })
+                .collect(),
+        ),
+    ];
+
+    let mut content = String::new();
+    for (name, help, values) in gauges {
+        let _ = writeln!(content, "# HELP {name} {help}.");
+        let _ = writeln!(content, "# TYPE {name} gauge");
+        for (outcome, value) in outcomes.iter().zip(values) {
+            let Some(value) = value else {
+                continue;
+            };
+            let _ = writeln!(
+                content,
+                "{name}{{profile=\"{}\"}} {value}",
+                escape(&outcome.profile_path.to_string_lossy())
+            );
+        }
+    }
+
+    // The collector only reads `*.prom`, keep the full name to not clash with
+    // another file of the same stem
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    fs::write(&temp, content).with_context(|| format!("failed to write metrics to {:?}", temp))?;
+    fs::rename(&temp, path).with_context(|| format!("failed to write metrics to {:?}", path))
+}
+
+/// Escape a label value as the text format requires.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn outcome(profile_path: PathBuf, units: Option<UnitCounts>) -> Outcome {
+        Outcome {
+            profile_path,
+            removed_files: 3,
+            removed_directories: 1,
+            reclaimed_bytes: 4096,
+            failed: 1,
+            unreadable: 0,
+            skipped: 0,
+            units,
+            summary: String::new(),
+        }
+    }
+
+    #[test]
+    fn writes_a_gauge_of_each_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let debug = dir.path().join("debug");
+        let release = dir.path().join("release");
+        fs::create_dir(&debug).unwrap();
+        fs::create_dir(&release).unwrap();
+        fs::write(debug.join("file"), "x".repeat(10)).unwrap();
+        let units = UnitCounts { fresh: 7, dirty: 2 };
+        let path = dir.path().join("cargo_gc.prom");
+        write(
+            &path,
+            &[
+                outcome(debug.clone(), Some(units)),
+                outcome(release.clone(), None),
+            ],
+            true,
+        )
+        .unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let mut samples = HashMap::new();
+        for line in content.lines().filter(|line| !line.starts_with('#')) {
+            let (metric, value) = line.rsplit_once(' ').unwrap();
+            let (name, labels) = metric.split_once('{').unwrap();
+            let profile = labels
+                .strip_prefix("profile=\"")
+                .and_then(|labels| labels.strip_suffix("\"}"))
+                .unwrap();
+            samples.insert(
+                (name.to_string(), PathBuf::from(profile)),
+                value.parse::<u64>().unwrap(),
+            );
+        }
+        let sample = |name: &str, profile: &Path| samples.get(&(name.to_string(), profile.into()));
+        assert_eq!(sample("cargo_gc_reclaimed_bytes", &debug), Some(&4096));
+        assert_eq!(sample("cargo_gc_removed_files", &release), Some(&3));
+        assert_eq!(sample("cargo_gc_failed_entries", &debug), Some(&1));
+        assert_eq!(sample("cargo_gc_fresh_units", &debug), Some(&7));
+        assert_eq!(sample("cargo_gc_dirty_units", &debug), Some(&2));
+        assert_eq!(sample("cargo_gc_fresh_units", &release), None);
+        assert_eq!(sample("cargo_gc_target_size_bytes", &debug), Some(&10));
+        assert_eq!(sample("cargo_gc_target_size_bytes", &release), Some(&0));
+        assert!(content.contains("# TYPE cargo_gc_dirty_units gauge"));
+        assert!(!dir.path().join("cargo_gc.prom.tmp").exists());
+    }
+}
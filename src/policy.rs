@@ -0,0 +1,147 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use cargo_metadata::Package;
+use serde::Deserialize;
+
+use crate::normalize_crate_name;
+
+/// Settings a workspace member declares in `[package.metadata.cargo-gc]`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct PackageSettings {
+    /// Number of incremental compilation sessions to keep for each unit
+    keep_last: Option<usize>,
+    /// Never remove anything built from this package
+    never_gc: bool,
+}
+
+/// Retention settings of workspace members by crate name. Flags given on the
+/// command line take precedence over them.
+#[derive(Default)]
+pub struct Policy {
+    keep_last: HashMap<String, usize>,
+    pub never_gc: HashSet<String>,
+    /// Binary and example targets of workspace members
+    pub binaries: HashSet<String>,
+}
+
+impl Policy {
+    /// Settings of `packages`, with the `excluded` ones never GC'ed as if they
+    /// set `never_gc`.
+    pub fn from_packages(packages: &[Package], excluded: &[String]) -> Result<Self> {
+        if let Some(name) = excluded
+            .iter()
+            .find(|name| !packages.iter().any(|package| package.name == **name))
+        {
+            anyhow::bail!("package `{}` is not a member of the workspace", name);
+        }
+        let mut policy = Policy::default();
+        for package in packages {
+            if excluded.contains(&package.name) {
+                policy.exclude(package);
+            }
+            policy.binaries.extend(
+                package
+                    .targets
+                    .iter()
+                    .filter(|target| {
+                        target
+                            .kind
+                            .iter()
+                            .any(|kind| kind == "bin" || kind == "example")
+                    })
+                    .map(|target| normalize_crate_name(&target.name)),
+            );
+            let Some(settings) = package.metadata.get("cargo-gc") else {
+                continue;
+            };
+            let settings = PackageSettings::deserialize(settings).with_context(|| {
+                format!(
+                    "invalid [package.metadata.cargo-gc] in {:?}",
+                    package.manifest_path
+                )
+            })?;
+            for target in &package.targets {
+                let name = normalize_crate_name(&target.name);
+                if let Some(keep_last) = settings.keep_last {
+                    policy.keep_last.insert(name, keep_last);
+                }
+            }
+            if settings.never_gc {
+                policy.exclude(package);
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Never GC anything built from the package, including its build script
+    /// which is named by the package.
+    fn exclude(&mut self, package: &Package) {
+        self.never_gc.extend(
+            package
+                .targets
+                .iter()
+                .map(|target| normalize_crate_name(&target.name))
+                .chain([normalize_crate_name(&package.name)]),
+        );
+    }
+
+    /// Number of incremental sessions to keep for each unit of a crate, if it
+    /// has its own setting or `keep_incremental` is given on the command line.
+    /// Crates never GC'ed keep all of them regardless of the command line.
+    pub fn keep_incremental(&self, name: &str, keep_incremental: Option<usize>) -> Option<usize> {
+        if self.never_gc.contains(name) {
+            return Some(usize::MAX);
+        }
+        keep_incremental.or_else(|| self.keep_last.get(name).copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_gc_wins_over_command_line() {
+        let mut policy = Policy::default();
+        policy.never_gc.insert("pinned".to_string());
+        policy.keep_last.insert("kept".to_string(), 3);
+
+        assert_eq!(policy.keep_incremental("pinned", Some(1)), Some(usize::MAX));
+        assert_eq!(policy.keep_incremental("kept", Some(1)), Some(1));
+        assert_eq!(policy.keep_incremental("kept", None), Some(3));
+        assert_eq!(policy.keep_incremental("other", None), None);
+    }
+
+    /// A package with a lib target, as `cargo metadata` reports it.
+    fn package(name: &str) -> Package {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "version": "0.1.0",
+            "id": format!("{name} 0.1.0 (path+file:///{name})"),
+            "dependencies": [],
+            "targets": [{
+                "name": name,
+                "kind": ["lib"],
+                "crate_types": ["lib"],
+                "src_path": format!("/{name}/src/lib.rs"),
+            }],
+            "features": {},
+            "manifest_path": format!("/{name}/Cargo.toml"),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn excluded_member_wins_over_command_line() {
+        let packages = [package("foo-core"), package("bar")];
+        let policy = Policy::from_packages(&packages, &["foo-core".to_string()]).unwrap();
+
+        assert_eq!(
+            policy.keep_incremental("foo_core", Some(1)),
+            Some(usize::MAX)
+        );
+        assert_eq!(policy.keep_incremental("bar", Some(1)), Some(1));
+    }
+}
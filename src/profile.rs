@@ -0,0 +1,85 @@
+use std::{collections::BTreeSet, fs, path::Path};
+
+use anyhow::{Context, Result};
+
+use crate::build_target::config_files;
+
+/// Profiles cargo defines without any configuration.
+const BUILTIN_PROFILES: [&str; 4] = ["bench", "dev", "release", "test"];
+
+/// Fail if any of `profiles` is not defined, before running `cargo build` which
+/// only finds out after gc spent time on it. Custom profiles are declared in
+/// `[profile.<name>]` of the workspace's Cargo.toml or cargo's config.
+pub fn check_defined(profiles: &[String], manifest_path: &Path) -> Result<()> {
+    // `debug` names the `dev` profile by its directory, gc builds it without `--profile`
+    let profiles = profiles
+        .iter()
+        .map(|profile| match profile.as_str() {
+            "debug" => "dev",
+            profile => profile,
+        })
+        .collect::<Vec<_>>();
+    if profiles
+        .iter()
+        .all(|profile| BUILTIN_PROFILES.contains(profile))
+    {
+        return Ok(());
+    }
+    let mut defined = BUILTIN_PROFILES
+        .iter()
+        .map(|profile| profile.to_string())
+        .collect::<BTreeSet<_>>();
+    for path in [manifest_path.to_path_buf()]
+        .into_iter()
+        .chain(config_files()?)
+    {
+        defined.extend(declared_profiles(&path)?);
+    }
+    if let Some(profile) = profiles.iter().find(|profile| !defined.contains(**profile)) {
+        anyhow::bail!(
+            "profile `{}` is not defined, available profiles: {}",
+            profile,
+            defined.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Names in the `[profile]` table of a manifest or config file.
+fn declared_profiles(path: &Path) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    let table: toml::Table =
+        toml::from_str(&content).with_context(|| format!("failed to parse {:?}", path))?;
+    Ok(match table.get("profile") {
+        Some(toml::Value::Table(profiles)) => profiles.keys().cloned().collect(),
+        _ => vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(content: &str) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("Cargo.toml"), content).unwrap();
+        dir
+    }
+
+    #[test]
+    fn debug_is_an_alias_of_dev() {
+        let dir = manifest("[package]\nname = \"foo\"\n");
+        let manifest_path = dir.path().join("Cargo.toml");
+
+        assert!(check_defined(&["debug".to_string()], &manifest_path).is_ok());
+    }
+
+    #[test]
+    fn custom_profiles_must_be_declared() {
+        let dir = manifest("[profile.ci]\ninherits = \"dev\"\n");
+        let manifest_path = dir.path().join("Cargo.toml");
+
+        assert!(check_defined(&["ci".to_string(), "debug".to_string()], &manifest_path).is_ok());
+        assert!(check_defined(&["nightly".to_string()], &manifest_path).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    args::Args, dir_size, is_cache_dir_tagged, lockfile, metadata_command, normalize_crate_name,
+    SizeCounter,
+};
+
+/// Directories under the profile directory holding one `<name>-<hash>` entry
+/// per build of a crate.
+const CRATE_DIRS: [&str; 3] = [".fingerprint", "build", "incremental"];
+
+/// Normalized names of all packages in `Cargo.lock` and the crates they build.
+pub fn live_crates(args: &Args) -> Result<HashSet<String>> {
+    let metadata = metadata_command(args)
+        .exec()
+        .context("failed to retrieve cargo metadata")?;
+    lockfile::live_crates(&metadata)
+}
+
+/// (Path, Size) of entries in `.fingerprint`, `build` and `incremental` of
+/// crates that are no longer dependencies.
+pub fn removed_directories(
+    profile_path: &Path,
+    live_crates: &HashSet<String>,
+    size_counter: &mut SizeCounter,
+) -> Result<Vec<(PathBuf, u64)>> {
+    let mut removed = vec![];
+    for crate_dir in CRATE_DIRS {
+        let path = profile_path.join(crate_dir);
+        if !path.is_dir() {
+            continue;
+        }
+        let entries =
+            fs::read_dir(&path).with_context(|| format!("failed to read directory: {:?}", path))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("failed to read entry in {:?}", path))?;
+            if !entry.file_type().is_ok_and(|file_type| file_type.is_dir())
+                || is_cache_dir_tagged(&entry.path())
+            {
+                continue;
+            }
+            let dir_name = entry.file_name().to_string_lossy().to_string();
+            let Some((name, _)) = dir_name.rsplit_once('-') else {
+                continue;
+            };
+            if live_crates.contains(&normalize_crate_name(name)) {
+                continue;
+            }
+            let size = dir_size(&entry.path(), size_counter);
+            removed.push((entry.path(), size));
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_directories_of_removed_crates_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let profile_path = dir.path();
+        let removed_dirs = [
+            ".fingerprint/gone-0123456789abcdef",
+            "build/gone-fedcba9876543210",
+            "incremental/gone-1m2n3o4p5q6r7",
+        ];
+        let live_dirs = [
+            ".fingerprint/app-0123456789abcdef",
+            "build/app-fedcba9876543210",
+            "incremental/app-1m2n3o4p5q6r7",
+        ];
+        for path in removed_dirs.iter().chain(&live_dirs) {
+            fs::create_dir_all(profile_path.join(path)).unwrap();
+        }
+        fs::write(profile_path.join(removed_dirs[0]).join("lib-gone"), "x").unwrap();
+
+        let mut removed = removed_directories(
+            profile_path,
+            &HashSet::from(["app".to_string()]),
+            &mut SizeCounter::new(true),
+        )
+        .unwrap();
+        removed.sort();
+        assert_eq!(
+            removed,
+            [
+                (profile_path.join(removed_dirs[0]), 1),
+                (profile_path.join(removed_dirs[1]), 0),
+                (profile_path.join(removed_dirs[2]), 0),
+            ]
+        );
+    }
+}
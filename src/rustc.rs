@@ -0,0 +1,135 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::{deps, dir_size, SizeCounter};
+
+/// The part of a fingerprint JSON file in `.fingerprint/<name>-<figureprint>`
+/// telling the compiler. Cargo records a hash of `rustc -vV`.
+#[derive(Deserialize)]
+struct Fingerprint {
+    rustc: u64,
+}
+
+/// Figureprints of units built by a rustc other than the one that wrote the
+/// most recent fingerprint, which is taken as the active toolchain.
+pub fn other_rustc_figureprints(profile_path: &Path) -> Result<HashSet<String>> {
+    // (Figureprint, Rustc hash, Modified)
+    let mut stamps = vec![];
+    for (path, _, figureprint) in deps::units(&profile_path.join(".fingerprint"))?.entries {
+        let Ok(entries) = fs::read_dir(&path) else {
+            continue;
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if entry.path().extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(fingerprint) = fs::read_to_string(entry.path())
+                .ok()
+                .and_then(|json| serde_json::from_str::<Fingerprint>(&json).ok())
+            else {
+                continue;
+            };
+            let modified = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            stamps.push((figureprint.clone(), fingerprint.rustc, modified));
+        }
+    }
+
+    let Some(active) = stamps
+        .iter()
+        .max_by_key(|(_, _, modified)| *modified)
+        .map(|(_, rustc, _)| *rustc)
+    else {
+        return Ok(HashSet::new());
+    };
+    Ok(stamps
+        .into_iter()
+        .filter(|(_, rustc, _)| *rustc != active)
+        .map(|(figureprint, _, _)| figureprint)
+        .collect())
+}
+
+/// (Path, Size) of `.fingerprint` and `build` directories of the figureprints,
+/// except those of packages that `keep`.
+pub fn unit_directories(
+    profile_path: &Path,
+    figureprints: &HashSet<String>,
+    keep: &dyn Fn(&str) -> bool,
+    size_counter: &mut SizeCounter,
+) -> Result<Vec<(PathBuf, u64)>> {
+    let mut directories = vec![];
+    for unit_dir in [".fingerprint", "build"] {
+        for (path, name, figureprint) in deps::units(&profile_path.join(unit_dir))?.entries {
+            if figureprints.contains(&figureprint) && !keep(&name) {
+                let size = dir_size(&path, size_counter);
+                directories.push((path, size));
+            }
+        }
+    }
+    Ok(directories)
+}
+
+#[cfg(test)]
+mod tests {
+    use filetime::FileTime;
+
+    use super::*;
+
+    /// Write the fingerprint JSON of a lib unit built by the `rustc` hash.
+    fn fingerprint(profile_path: &Path, unit: &str, rustc: u64, modified: i64) {
+        let dir = profile_path.join(".fingerprint").join(unit);
+        fs::create_dir_all(&dir).unwrap();
+        let name = unit.rsplit_once('-').unwrap().0;
+        fs::write(dir.join(format!("lib-{name}")), "0123456789abcdef").unwrap();
+        let json = dir.join(format!("lib-{name}.json"));
+        fs::write(
+            &json,
+            format!(r#"{{"rustc":{rustc},"features":"[]","target":1,"profile":2}}"#),
+        )
+        .unwrap();
+        filetime::set_file_mtime(&json, FileTime::from_unix_time(modified, 0)).unwrap();
+    }
+
+    #[test]
+    fn finds_units_of_an_older_compiler() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join(".rustc_info.json"),
+            r#"{"rustc_fingerprint":1,"outputs":{}}"#,
+        )
+        .unwrap();
+        let profile_path = dir.path().join("debug");
+        fingerprint(&profile_path, "foo-0123456789abcdef", 1111, 1_000);
+        fingerprint(&profile_path, "bar-0123456789abcdef", 1111, 1_000);
+        fingerprint(&profile_path, "foo-fedcba9876543210", 2222, 2_000);
+        fingerprint(&profile_path, "bar-fedcba9876543210", 2222, 3_000);
+        // Not a fingerprint of a unit
+        fs::write(
+            profile_path.join(".fingerprint/bar-fedcba9876543210/invoked.timestamp"),
+            "",
+        )
+        .unwrap();
+
+        assert_eq!(
+            other_rustc_figureprints(&profile_path).unwrap(),
+            HashSet::from(["0123456789abcdef".to_string()])
+        );
+    }
+
+    #[test]
+    fn finds_nothing_without_fingerprints() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join(".fingerprint")).unwrap();
+
+        assert!(other_rustc_figureprints(dir.path()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{deps::Artifact, file_size, incremental::IncrementalAnalysis, Plan};
+
+/// A removal plan written by `--output-plan`, to be reviewed and executed
+/// later by `--apply-plan`. Paths are relative to the target directory, so a
+/// plan can be applied to a copy of it elsewhere.
+#[derive(Serialize, Deserialize)]
+struct SavedPlan {
+    /// Target directory the plan is written for
+    target_directory: PathBuf,
+    profile_path: PathBuf,
+    /// Whether sizes are apparent sizes, needed to validate them
+    apparent_size: bool,
+    files: Vec<Artifact>,
+    incremental_crates: usize,
+    incremental: Vec<Directory>,
+    /// Missing in plans written by older versions
+    #[serde(default)]
+    unit_dirs: Vec<Directory>,
+    removed_crates: Vec<Directory>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Directory {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl Directory {
+    fn new((path, size): &(PathBuf, u64)) -> Self {
+        Self {
+            path: path.clone(),
+            size: *size,
+            modified: modified(path).unwrap_or(SystemTime::UNIX_EPOCH),
+        }
+    }
+
+    /// A directory is changed if anything is added or removed in it.
+    fn is_unchanged(&self) -> bool {
+        modified(&self.path) == Some(self.modified)
+    }
+}
+
+pub fn write(plan: &Plan, path: &Path, apparent_size: bool) -> Result<()> {
+    let relative = |path: &Path| {
+        path.strip_prefix(&plan.target_directory)
+            .unwrap_or(path)
+            .to_path_buf()
+    };
+    let directories = |directories: &[(PathBuf, u64)]| {
+        directories
+            .iter()
+            .map(|directory| {
+                let mut saved = Directory::new(directory);
+                saved.path = relative(&saved.path);
+                saved
+            })
+            .collect()
+    };
+    let saved = SavedPlan {
+        target_directory: plan.target_directory.clone(),
+        profile_path: relative(&plan.profile_path),
+        apparent_size,
+        files: plan
+            .files
+            .iter()
+            .map(|artifact| Artifact {
+                path: relative(&artifact.path),
+                ..artifact.clone()
+            })
+            .collect(),
+        incremental_crates: plan.incremental.crates,
+        incremental: directories(&plan.incremental.outdated),
+        unit_dirs: directories(&plan.unit_dirs),
+        removed_crates: directories(&plan.removed_crates),
+    };
+    let json = serde_json::to_string_pretty(&saved).context("failed to serialize plan")?;
+    fs::write(path, json).with_context(|| format!("failed to write plan to {:?}", path))
+}
+
+/// Load a plan for `target_directory`, or the one it's written for if `None`,
+/// leaving out files and directories changed since it was written. The second
+/// value is the number of entries left out.
+///
+/// Every entry is checked before anything is removed, a plan with one outside
+/// the profile directory is rejected as a whole.
+pub fn load(path: &Path, target_directory: Option<&Path>, quiet: bool) -> Result<(Plan, usize)> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("failed to read plan {:?}", path))?;
+    let mut saved: SavedPlan = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse plan {:?}", path))?;
+
+    let target_directory = target_directory
+        .map(Path::to_path_buf)
+        .unwrap_or(saved.target_directory);
+    let invalid = || format!("invalid plan {:?}", path);
+    let profile_path =
+        rebase(&target_directory, &saved.profile_path, &target_directory).with_context(invalid)?;
+    saved.profile_path = profile_path.clone();
+    for artifact in &mut saved.files {
+        artifact.path =
+            rebase(&target_directory, &artifact.path, &profile_path).with_context(invalid)?;
+    }
+    for directory in saved
+        .incremental
+        .iter_mut()
+        .chain(&mut saved.unit_dirs)
+        .chain(&mut saved.removed_crates)
+    {
+        directory.path =
+            rebase(&target_directory, &directory.path, &profile_path).with_context(invalid)?;
+    }
+
+    let mut skipped = 0;
+    let mut report_changed = |path: &Path| {
+        skipped += 1;
+        if !quiet {
+            eprintln!("skipped {:?} (changed since the plan was written)", path);
+        }
+    };
+    let mut files = vec![];
+    for artifact in saved.files {
+        let unchanged = fs::metadata(&artifact.path).is_ok_and(|metadata| {
+            file_size(&metadata, saved.apparent_size) == artifact.size
+                && metadata.modified().ok() == Some(artifact.modified)
+        });
+        if unchanged {
+            files.push(artifact);
+        } else {
+            report_changed(&artifact.path);
+        }
+    }
+    let mut directories = |saved: Vec<Directory>| {
+        let mut unchanged = vec![];
+        for directory in saved {
+            if directory.is_unchanged() {
+                unchanged.push((directory.path, directory.size));
+            } else {
+                report_changed(&directory.path);
+            }
+        }
+        unchanged
+    };
+    let incremental = directories(saved.incremental);
+    let unit_dirs = directories(saved.unit_dirs);
+    let removed_crates = directories(saved.removed_crates);
+
+    let plan = Plan {
+        target_directory,
+        profile_path: saved.profile_path,
+        files,
+        incremental: IncrementalAnalysis {
+            crates: saved.incremental_crates,
+            outdated: incremental,
+            ..IncrementalAnalysis::default()
+        },
+        unit_dirs,
+        removed_crates,
+        unreadable: 0,
+        units: None,
+        // Entries are validated one by one instead
+        deps_modified: None,
+        build_time: Duration::ZERO,
+        scan_time: Duration::ZERO,
+    };
+    Ok((plan, skipped))
+}
+
+/// A path saved relative to the target directory, joined onto the one the plan
+/// is applied to. It must be inside `inside`, so an edited plan can't remove
+/// anything elsewhere.
+fn rebase(target_directory: &Path, path: &Path, inside: &Path) -> Result<PathBuf> {
+    let is_relative = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    let rebased = target_directory.join(path);
+    if !is_relative || !rebased.starts_with(inside) || rebased == inside {
+        anyhow::bail!("{:?} is not inside {:?}", path, inside);
+    }
+    Ok(rebased)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).ok()?.modified().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A target directory with an outdated file and incremental session, and
+    /// the plan to remove them.
+    fn target_with_plan() -> (tempfile::TempDir, Plan) {
+        let dir = tempfile::tempdir().unwrap();
+        let target_directory = dir.path().to_path_buf();
+        let profile_path = target_directory.join("debug");
+        let file = profile_path.join("deps/libfoo-0123456789abcdef.rlib");
+        let session = profile_path.join("incremental/foo-1a2b3c/s-old-abc-def");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "rlib").unwrap();
+        fs::create_dir_all(&session).unwrap();
+        let metadata = fs::metadata(&file).unwrap();
+        let plan = Plan {
+            target_directory,
+            profile_path,
+            files: vec![Artifact {
+                path: file,
+                name: "libfoo".to_string(),
+                figureprint: "0123456789abcdef".to_string(),
+                is_dep_info: false,
+                size: file_size(&metadata, true),
+                modified: metadata.modified().unwrap(),
+            }],
+            incremental: IncrementalAnalysis {
+                crates: 1,
+                outdated: vec![(session, 0)],
+                ..IncrementalAnalysis::default()
+            },
+            unit_dirs: vec![],
+            removed_crates: vec![],
+            unreadable: 0,
+            units: None,
+            deps_modified: None,
+            build_time: Duration::ZERO,
+            scan_time: Duration::ZERO,
+        };
+        (dir, plan)
+    }
+
+    /// Replace the path of the first entry of `list` in the plan at `path`.
+    fn tamper(path: &Path, list: &str, entry: &str) {
+        let mut saved: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        saved[list][0]["path"] = entry.into();
+        fs::write(path, saved.to_string()).unwrap();
+    }
+
+    #[test]
+    fn rejects_entries_outside_the_profile_directory() {
+        let (dir, plan) = target_with_plan();
+        let plan_path = dir.path().join("plan.json");
+        for (list, entry) in [
+            ("files", "debug/../../victim"),
+            ("files", "/tmp/victim"),
+            ("incremental", "release/incremental/foo-1a2b3c"),
+            ("incremental", "debug"),
+        ] {
+            write(&plan, &plan_path, true).unwrap();
+            tamper(&plan_path, list, entry);
+            let error = load(&plan_path, None, true).err().unwrap();
+            assert!(
+                format!("{:#}", error).contains("is not inside"),
+                "{entry}: {error:#}"
+            );
+        }
+    }
+
+    #[test]
+    fn applies_to_a_copy_of_the_target_directory() {
+        let (dir, plan) = target_with_plan();
+        let plan_path = dir.path().join("plan.json");
+        write(&plan, &plan_path, true).unwrap();
+        // Moving keeps modification times
+        let copy = tempfile::tempdir().unwrap();
+        let moved = copy.path().join("target");
+        fs::create_dir(&moved).unwrap();
+        fs::rename(dir.path().join("debug"), moved.join("debug")).unwrap();
+
+        let (loaded, skipped) = load(&plan_path, Some(&moved), true).unwrap();
+        assert_eq!(skipped, 0);
+        assert_eq!(loaded.profile_path, moved.join("debug"));
+        assert_eq!(
+            loaded.files[0].path,
+            moved.join("debug/deps/libfoo-0123456789abcdef.rlib")
+        );
+        assert_eq!(loaded.incremental.crates, 1);
+        assert_eq!(
+            loaded.incremental.outdated,
+            vec![(moved.join("debug/incremental/foo-1a2b3c/s-old-abc-def"), 0)]
+        );
+    }
+
+    #[test]
+    fn skips_entries_changed_since_written() {
+        let (dir, plan) = target_with_plan();
+        let plan_path = dir.path().join("plan.json");
+        write(&plan, &plan_path, true).unwrap();
+        fs::write(&plan.files[0].path, "rebuilt").unwrap();
+        fs::create_dir(plan.incremental.outdated[0].0.join("new")).unwrap();
+
+        let (loaded, skipped) = load(&plan_path, None, true).unwrap();
+        assert_eq!(skipped, 2);
+        assert!(loaded.files.is_empty());
+        assert!(loaded.incremental.outdated.is_empty());
+    }
+}
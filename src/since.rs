@@ -0,0 +1,119 @@
+use std::{collections::HashSet, path::Path, process::Command};
+
+use anyhow::Result;
+use cargo_metadata::Package;
+
+use crate::{normalize_crate_name, UsageError};
+
+/// Crates of the packages having files changed since the git ref `since`. It
+/// fails if git can't tell, e.g. for a mistyped ref or if the workspace isn't
+/// in a git repository, rather than GC'ing every crate.
+pub fn changed_crates(
+    workspace_root: &Path,
+    packages: &[Package],
+    since: &str,
+) -> Result<HashSet<String>> {
+    let output = Command::new("git")
+        .args(["diff", "--name-only", "--relative", since])
+        .current_dir(workspace_root)
+        .output()
+        .map_err(|e| UsageError(format!("--since needs git, failed to run it: {}", e)))?;
+    if !output.status.success() {
+        return Err(UsageError(format!(
+            "invalid --since `{}`, git diff failed: {}",
+            since,
+            String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .next()
+                .unwrap_or_default()
+        ))
+        .into());
+    }
+
+    let mut crates = HashSet::new();
+    for changed in String::from_utf8_lossy(&output.stdout).lines() {
+        let changed = workspace_root.join(changed);
+        // The innermost package owns the file, members can be nested
+        let owner = packages
+            .iter()
+            .filter(|package| {
+                package
+                    .manifest_path
+                    .parent()
+                    .is_some_and(|dir| changed.starts_with(dir))
+            })
+            .max_by_key(|package| package.manifest_path.as_str().len());
+        if let Some(package) = owner {
+            crates.extend(
+                package
+                    .targets
+                    .iter()
+                    .map(|target| normalize_crate_name(&target.name)),
+            );
+        }
+    }
+    Ok(crates)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use cargo_metadata::MetadataCommand;
+
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=gc", "-c", "user.email=gc@localhost"])
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn maps_changed_files_to_their_innermost_package() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Cargo.toml"),
+            "[workspace]\nmembers = [\"core\", \"core/plugin\"]\nresolver = \"2\"\n",
+        )
+        .unwrap();
+        for (path, name) in [("core", "core"), ("core/plugin", "plugin")] {
+            fs::create_dir_all(dir.path().join(path).join("src")).unwrap();
+            fs::write(
+                dir.path().join(path).join("Cargo.toml"),
+                format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n"),
+            )
+            .unwrap();
+            fs::write(dir.path().join(path).join("src/lib.rs"), "").unwrap();
+        }
+        fs::write(dir.path().join("README.md"), "").unwrap();
+        git(dir.path(), &["init", "--quiet"]);
+        git(dir.path(), &["add", "."]);
+        git(dir.path(), &["commit", "--quiet", "-m", "initial"]);
+        let packages = MetadataCommand::new()
+            .manifest_path(dir.path().join("Cargo.toml"))
+            .no_deps()
+            .other_options(vec!["--offline".to_string()])
+            .exec()
+            .unwrap()
+            .packages;
+
+        // Outside of any package, and in the nested one
+        fs::write(dir.path().join("README.md"), "changed").unwrap();
+        fs::write(dir.path().join("core/plugin/src/lib.rs"), "changed").unwrap();
+        assert_eq!(
+            changed_crates(dir.path(), &packages, "HEAD").unwrap(),
+            HashSet::from(["plugin".to_string()])
+        );
+
+        fs::write(dir.path().join("core/src/lib.rs"), "changed").unwrap();
+        assert_eq!(
+            changed_crates(dir.path(), &packages, "HEAD").unwrap(),
+            HashSet::from(["core".to_string(), "plugin".to_string()])
+        );
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use console::style;
+use humansize::DECIMAL;
+use serde::Serialize;
+
+use crate::{
+    args::{Args, Format},
+    deps::{self, Artifact},
+    export::Snapshot,
+    filesystem::OsFileSystem,
+    get_figureprints, Figureprints, SizeCounter, Workspace,
+};
+
+/// How many crates are listed in the "largest crates" section.
+pub const LARGEST_CRATES: usize = 10;
+
+#[derive(Serialize)]
+struct ProfileStats {
+    profile: String,
+    /// Whether the outdated artifacts are estimated from modification time
+    /// instead of the output of `cargo build`.
+    estimated: bool,
+    total: Usage,
+    in_use: Usage,
+    outdated: Usage,
+    /// `.fingerprint` and `build` directories of the outdated artifacts, not
+    /// known for snapshots
+    outdated_directories: Option<Usage>,
+    largest_crates: Vec<CrateStats>,
+}
+
+#[derive(Serialize, Default)]
+struct Usage {
+    files: usize,
+    bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+    }
+}
+
+#[derive(Serialize)]
+struct CrateStats {
+    name: String,
+    builds: usize,
+    reclaimable_bytes: u64,
+}
+
+pub fn run(args: &Args, workspace: &Workspace, all_profiles: bool, build: bool) -> Result<()> {
+    let target_path = &workspace.profiles_directory();
+    let figureprints = if build {
+        Some(get_figureprints(args, workspace)?)
+    } else {
+        None
+    };
+
+    let profiles = if all_profiles {
+        list_profiles(target_path)?
+    } else {
+        vec![args.profile_dir().to_string()]
+    };
+
+    let mut stats = Vec::with_capacity(profiles.len());
+    for profile in profiles {
+        let profile_path = target_path.join(&profile);
+        let artifacts = deps::scan(
+            &OsFileSystem,
+            &profile_path.join("deps"),
+            args.apparent_size,
+            args.verbose,
+        )?;
+        stats.push(profile_stats(
+            profile,
+            artifacts,
+            figureprints.as_ref(),
+            Some((&profile_path, args.apparent_size)),
+        )?);
+    }
+
+    print(args, &stats)
+}
+
+/// Show statistics of a snapshot written by `cargo gc export`.
+pub fn replay(args: &Args, snapshot: &Path) -> Result<()> {
+    let snapshot = Snapshot::load(snapshot)?;
+    let stats = profile_stats(
+        snapshot.profile,
+        snapshot.artifacts,
+        snapshot.figureprints.as_ref(),
+        None,
+    )?;
+    print(args, &[stats])
+}
+
+fn print(args: &Args, stats: &[ProfileStats]) -> Result<()> {
+    match args.format {
+        Format::Text => stats.iter().for_each(print_profile_stats),
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(stats).context("failed to serialize stats")?
+        ),
+    }
+    Ok(())
+}
+
+/// Profile directories are the ones containing a `deps` directory.
+fn list_profiles(target_path: &Path) -> Result<Vec<String>> {
+    let mut profiles = vec![];
+    let entries = fs::read_dir(target_path)
+        .with_context(|| format!("failed to read target directory: {:?}", target_path))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("failed to read entry in {:?}", target_path))?;
+        if entry.path().join("deps").is_dir() {
+            profiles.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    profiles.sort();
+    Ok(profiles)
+}
+
+/// Statistics of the artifacts, and of the directories of outdated ones if
+/// the (Profile path, Apparent size) they are in is given.
+fn profile_stats(
+    profile: String,
+    artifacts: Vec<Artifact>,
+    figureprints: Option<&Figureprints>,
+    profile_path: Option<(&Path, bool)>,
+) -> Result<ProfileStats> {
+    let built = figureprints;
+    let estimated = figureprints.is_none();
+    let estimation;
+    let figureprints = match figureprints {
+        Some(figureprints) => figureprints,
+        None => {
+            estimation = deps::newest_figureprints(&artifacts);
+            &estimation
+        }
+    };
+
+    let mut total = Usage::default();
+    for artifact in &artifacts {
+        total.add(artifact.size);
+    }
+
+    let outdated_artifacts = deps::outdated(artifacts, figureprints);
+    let outdated_directories = match profile_path {
+        Some((profile_path, apparent_size)) => {
+            let mut usage = Usage::default();
+            // Build scripts in use are only known from a build
+            let (directories, _) = deps::unit_directories(
+                profile_path,
+                &outdated_artifacts,
+                built,
+                &|_| false,
+                &mut SizeCounter::new(apparent_size),
+            )?;
+            for (_, size) in directories {
+                usage.add(size);
+            }
+            Some(usage)
+        }
+        None => None,
+    };
+
+    let mut outdated = Usage::default();
+    // (Reclaimable bytes, Outdated figureprints) of each crate, a build is all
+    // the files of a figureprint
+    let mut crates: HashMap<String, (u64, HashSet<String>)> = HashMap::new();
+    for artifact in outdated_artifacts {
+        outdated.add(artifact.size);
+        let entry = crates.entry(artifact.crate_name().to_string()).or_default();
+        entry.0 += artifact.size;
+        entry.1.insert(artifact.figureprint);
+    }
+    let in_use = Usage {
+        files: total.files - outdated.files,
+        bytes: total.bytes - outdated.bytes,
+    };
+
+    let mut largest_crates = crates
+        .into_iter()
+        .map(|(name, (reclaimable_bytes, builds))| CrateStats {
+            name,
+            builds: builds.len(),
+            reclaimable_bytes,
+        })
+        .collect::<Vec<_>>();
+    largest_crates.sort_by(|a, b| {
+        b.reclaimable_bytes
+            .cmp(&a.reclaimable_bytes)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    largest_crates.truncate(LARGEST_CRATES);
+
+    Ok(ProfileStats {
+        profile,
+        estimated,
+        total,
+        in_use,
+        outdated,
+        outdated_directories,
+        largest_crates,
+    })
+}
+
+fn print_profile_stats(stats: &ProfileStats) {
+    let estimate_note = if stats.estimated {
+        " (estimated by modification time, pass `--build` for exact result)"
+    } else {
+        ""
+    };
+    println!(
+        "Profile {}: {} in {} files",
+        stats.profile,
+        humansize::format_size(stats.total.bytes, DECIMAL),
+        stats.total.files,
+    );
+    println!(
+        "  {}   {} in {} files",
+        style("in use:").green(),
+        humansize::format_size(stats.in_use.bytes, DECIMAL),
+        stats.in_use.files,
+    );
+    println!(
+        "  {} {} in {} files{}",
+        style("outdated:").red(),
+        humansize::format_size(stats.outdated.bytes, DECIMAL),
+        stats.outdated.files,
+        estimate_note,
+    );
+    if let Some(directories) = &stats.outdated_directories {
+        println!(
+            "  {} {} in {} .fingerprint and build directories",
+            style("outdated:").red(),
+            humansize::format_size(directories.bytes, DECIMAL),
+            directories.files,
+        );
+    }
+    if stats.largest_crates.is_empty() {
+        return;
+    }
+    println!("  largest crates by reclaimable size:");
+    for krate in &stats.largest_crates {
+        println!(
+            "    {}: {} in {} outdated build(s)",
+            krate.name,
+            humansize::format_size(krate.reclaimable_bytes, DECIMAL),
+            krate.builds,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::SystemTime};
+
+    use super::*;
+
+    fn artifact(file_name: &str, size: u64) -> Artifact {
+        let (stem, _) = file_name.split_once('.').unwrap_or((file_name, ""));
+        let (name, figureprint) = stem.rsplit_once('-').unwrap();
+        Artifact {
+            path: PathBuf::from("debug/deps").join(file_name),
+            name: name.to_string(),
+            figureprint: figureprint.to_string(),
+            is_dep_info: file_name.ends_with(".d"),
+            size,
+            modified: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn groups_the_lib_bin_and_dep_info_of_a_crate() {
+        let artifacts = vec![
+            artifact("libapp-0000000000000001.rlib", 100),
+            artifact("libapp-0000000000000001.rmeta", 10),
+            artifact("app-0000000000000001.d", 1),
+            artifact("app-0000000000000002", 1000),
+            artifact("app-0000000000000002.d", 1),
+            artifact("libdep-0000000000000003.rlib", 5),
+        ];
+        // Only `dep` is in use
+        let figureprints = [("libdep".to_string(), "0000000000000003".to_string())]
+            .into_iter()
+            .collect();
+
+        let stats =
+            profile_stats("debug".to_string(), artifacts, Some(&figureprints), None).unwrap();
+        assert_eq!(stats.outdated.files, 5);
+        assert_eq!(stats.outdated.bytes, 1112);
+        assert_eq!(stats.largest_crates.len(), 1);
+        let app = &stats.largest_crates[0];
+        assert_eq!(app.name, "app");
+        assert_eq!(app.builds, 2);
+        assert_eq!(app.reclaimable_bytes, 1112);
+    }
+
+    #[test]
+    fn sums_directories_of_outdated_units() {
+        let dir = tempfile::tempdir().unwrap();
+        // The fingerprint of an outdated lib, a build script no longer built and
+        // the fingerprint of the lib in use
+        for (unit, size) in [
+            (".fingerprint/app-0000000000000001", 1024),
+            ("build/app-0000000000000002", 2048),
+            (".fingerprint/dep-0000000000000003", 4096),
+        ] {
+            let unit = dir.path().join(unit);
+            fs::create_dir_all(&unit).unwrap();
+            fs::write(unit.join("file"), "x".repeat(size)).unwrap();
+        }
+        let artifacts = vec![
+            artifact("libapp-0000000000000001.rlib", 100),
+            artifact("libdep-0000000000000003.rlib", 5),
+        ];
+        let figureprints = [("libdep".to_string(), "0000000000000003".to_string())]
+            .into_iter()
+            .collect();
+
+        let stats = profile_stats(
+            "debug".to_string(),
+            artifacts,
+            Some(&figureprints),
+            Some((dir.path(), true)),
+        )
+        .unwrap();
+        let directories = stats.outdated_directories.unwrap();
+        assert_eq!((directories.files, directories.bytes), (2, 1024 + 2048));
+        assert_eq!(stats.outdated.bytes, 100);
+    }
+
+    #[test]
+    fn imported_snapshot_has_the_counts_of_the_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let deps = dir.path().join("deps");
+        fs::create_dir(&deps).unwrap();
+        for (file, size) in [
+            ("libapp-0000000000000001.rlib", 100),
+            ("app-0000000000000001.d", 1),
+            ("libapp-0000000000000002.rlib", 50),
+            ("libdep-0000000000000003.rlib", 5),
+        ] {
+            fs::write(deps.join(file), "x".repeat(size)).unwrap();
+        }
+        let artifacts = deps::scan(&OsFileSystem, &deps, true, false).unwrap();
+        let snapshot = Snapshot {
+            profile: "debug".to_string(),
+            artifacts: artifacts.clone(),
+            figureprints: Some(
+                [("libapp".to_string(), "0000000000000002".to_string())]
+                    .into_iter()
+                    .collect(),
+            ),
+        };
+        let path = dir.path().join("snapshot.json");
+        fs::write(&path, serde_json::to_string(&snapshot).unwrap()).unwrap();
+
+        let imported = Snapshot::load(&path).unwrap();
+        assert_eq!(imported.profile, "debug");
+        assert_eq!(imported.figureprints, snapshot.figureprints);
+        let exported = profile_stats(
+            snapshot.profile,
+            artifacts,
+            snapshot.figureprints.as_ref(),
+            None,
+        )
+        .unwrap();
+        let imported = profile_stats(
+            imported.profile,
+            imported.artifacts,
+            imported.figureprints.as_ref(),
+            None,
+        )
+        .unwrap();
+        for (exported, imported) in [
+            (&exported.total, &imported.total),
+            (&exported.in_use, &imported.in_use),
+            (&exported.outdated, &imported.outdated),
+        ] {
+            assert_eq!(
+                (exported.files, exported.bytes),
+                (imported.files, imported.bytes)
+            );
+        }
+        assert_eq!((imported.total.files, imported.outdated.files), (4, 3));
+        assert_eq!(imported.largest_crates.len(), 2);
+    }
+}
@@ -0,0 +1,1172 @@
+use std::{
+    fs,
+    path::Path,
+    process::Command,
+    time::{Duration, SystemTime},
+};
+
+/// A package with nothing to GC yet, built once so `cargo gc` finds a profile
+/// directory.
+fn project() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("Cargo.toml"),
+        "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+    )
+    .unwrap();
+    fs::create_dir_all(dir.path().join("src")).unwrap();
+    fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--offline", "--quiet"])
+        .current_dir(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    dir
+}
+
+/// A built virtual workspace of the `first` and `second` lib packages.
+fn virtual_workspace() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("Cargo.toml"),
+        "[workspace]\nmembers = [\"first\", \"second\"]\nresolver = \"2\"\n",
+    )
+    .unwrap();
+    for member in ["first", "second"] {
+        fs::create_dir_all(dir.path().join(member).join("src")).unwrap();
+        fs::write(
+            dir.path().join(member).join("Cargo.toml"),
+            format!("[package]\nname = \"{member}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n"),
+        )
+        .unwrap();
+        fs::write(dir.path().join(member).join("src/lib.rs"), "").unwrap();
+    }
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--offline", "--quiet"])
+        .current_dir(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    dir
+}
+
+/// (Stdout, Stderr) of `cargo gc` with `args` in `dir`.
+fn gc(dir: &Path, args: &[&str]) -> (String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+        .args(["gc", "--offline"])
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn json_summary_is_alone_on_stdout() {
+    let dir = project();
+
+    let (stdout, stderr) = gc(dir.path(), &["--format", "json"]);
+    let summary: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(summary.as_array().unwrap().len(), 1);
+    assert!(stderr.contains("Removed"));
+}
+
+#[test]
+fn text_summary_is_on_stdout() {
+    let dir = project();
+
+    let (stdout, stderr) = gc(dir.path(), &[]);
+    assert!(stdout.contains("Removed"));
+    assert!(stderr.contains("found"));
+    assert!(!stdout.contains("found"));
+}
+
+#[test]
+fn quiet_leaves_stdout_clean() {
+    let dir = project();
+
+    let (stdout, stderr) = gc(dir.path(), &["--quiet"]);
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "");
+
+    let (stdout, stderr) = gc(dir.path(), &["--quiet", "--format", "json"]);
+    assert!(serde_json::from_str::<serde_json::Value>(&stdout).is_ok());
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn since_fails_when_git_cant_tell() {
+    let dir = project();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+        .args(["gc", "--offline", "--dry-run", "--since", "no-such-ref"])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--since"));
+}
+
+#[test]
+fn refuses_while_cargo_holds_the_build_lock() {
+    let dir = project();
+    let deps = dir.path().join("target/debug/deps");
+    let older = deps.join("libstale-0123456789abcdef.rlib");
+    fs::write(&older, "").unwrap();
+    filetime::set_file_mtime(&older, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+    fs::write(deps.join("libstale-fedcba9876543210.rlib"), "").unwrap();
+    let lock = fs::File::open(dir.path().join("target/debug/.cargo-lock")).unwrap();
+    lock.lock().unwrap();
+
+    // `cargo build` would wait for the lock, GC without it
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+        .args(["gc", "--offline", "--newest-only"])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("another cargo process"));
+    assert!(older.exists());
+}
+
+#[test]
+fn keeps_a_unit_whole_when_one_of_its_files_is_newer_than_the_run() {
+    let dir = project();
+    let profile = dir.path().join("target/debug");
+    let hours_from_now = |hours: u64| {
+        filetime::FileTime::from_system_time(
+            SystemTime::now() + Duration::from_secs(hours * 60 * 60),
+        )
+    };
+    // An older unit of `stale` being rebuilt while gc runs, and its newest unit
+    let rlib = profile.join("deps/libstale-0123456789abcdef.rlib");
+    let rmeta = profile.join("deps/libstale-0123456789abcdef.rmeta");
+    let fingerprint = profile.join(".fingerprint/stale-0123456789abcdef");
+    fs::write(&rlib, "").unwrap();
+    filetime::set_file_mtime(&rlib, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+    fs::write(&rmeta, "").unwrap();
+    filetime::set_file_mtime(&rmeta, hours_from_now(1)).unwrap();
+    fs::create_dir(&fingerprint).unwrap();
+    let newest = profile.join("deps/libstale-fedcba9876543210.rlib");
+    fs::write(&newest, "").unwrap();
+    filetime::set_file_mtime(&newest, hours_from_now(2)).unwrap();
+
+    let (_, stderr) = gc(dir.path(), &["--newest-only"]);
+    assert!(stderr.contains("newer than run start"));
+    assert!(rlib.exists());
+    assert!(rmeta.exists());
+    assert!(fingerprint.exists());
+}
+
+#[test]
+fn cache_removes_crates_unused_by_the_lockfile() {
+    let dir = project();
+    let cargo_home = tempfile::tempdir().unwrap();
+    let cache = cargo_home
+        .path()
+        .join("registry/cache/index.crates.io-6f17d22bba15001f");
+    fs::create_dir_all(&cache).unwrap();
+    fs::write(cache.join("unused-0.1.0.crate"), "").unwrap();
+    let cache_gc = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+            .args(["gc", "--offline", "--quiet", "cache"])
+            .args(args)
+            .env("CARGO_HOME", cargo_home.path())
+            .current_dir(dir.path())
+            .output()
+            .unwrap()
+    };
+
+    let output = cache_gc(&[]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(output.stdout.is_empty() && output.stderr.is_empty());
+    assert!(cache.join("unused-0.1.0.crate").exists());
+
+    let output = cache_gc(&["--apply"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(output.stdout.is_empty() && output.stderr.is_empty());
+    assert!(!cache.join("unused-0.1.0.crate").exists());
+}
+
+#[test]
+fn falls_back_to_newest_only_when_the_build_fails() {
+    let dir = project();
+    let deps = dir.path().join("target/debug/deps");
+    let older = deps.join("libstale-0123456789abcdef.rlib");
+    let newer = deps.join("libstale-fedcba9876543210.rlib");
+    fs::write(&older, "").unwrap();
+    filetime::set_file_mtime(&older, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+    fs::write(&newer, "").unwrap();
+    fs::write(dir.path().join("src/lib.rs"), "fn broken(").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+        .args(["gc", "--offline"])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4));
+    assert!(older.exists());
+
+    let (_, stderr) = gc(dir.path(), &["--fallback-newest-only"]);
+    assert!(stderr.contains("falling back"));
+    assert!(!older.exists());
+    assert!(newer.exists());
+}
+
+#[test]
+fn retain_days_keeps_recent_and_newest_builds() {
+    let dir = project();
+    let deps = dir.path().join("target/debug/deps");
+    let days_ago = |days: u64| {
+        filetime::FileTime::from_system_time(
+            SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60),
+        )
+    };
+    let build = |name: &str, days: u64| {
+        let path = deps.join(name);
+        fs::write(&path, "").unwrap();
+        filetime::set_file_mtime(&path, days_ago(days)).unwrap();
+        path
+    };
+    // The only build of `old` is older than the retention, but the newest
+    let old = build("libold-0123456789abcdef.rlib", 30);
+    let recent = build("libtwice-0123456789abcdef.rlib", 1);
+    let ancient = build("libtwice-fedcba9876543210.rlib", 30);
+    let older_recent = build("libtwice-1111111111111111.rlib", 2);
+
+    gc(dir.path(), &["--retain-days", "7"]);
+    assert!(old.exists());
+    assert!(recent.exists());
+    assert!(older_recent.exists());
+    assert!(!ancient.exists());
+}
+
+#[test]
+fn protect_binaries_keeps_stale_bins_and_examples() {
+    let dir = project();
+    fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+    fs::create_dir_all(dir.path().join("examples")).unwrap();
+    fs::write(dir.path().join("examples/demo.rs"), "fn main() {}").unwrap();
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--offline", "--quiet", "--bins", "--examples"])
+        .current_dir(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let profile = dir.path().join("target/debug");
+    let stale = |path: &str| {
+        let path = profile.join(path);
+        fs::write(&path, "").unwrap();
+        filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+        path
+    };
+    let bin = stale("deps/app-0123456789abcdef");
+    let example = stale("examples/demo-0123456789abcdef");
+    let rlib = stale("deps/libapp-1111111111111111.rlib");
+
+    gc(dir.path(), &["--protect-binaries"]);
+    assert!(bin.exists());
+    assert!(example.exists());
+    assert!(!rlib.exists());
+
+    // Without the flag only the newest of them survive
+    gc(dir.path(), &[]);
+    assert!(!bin.exists());
+    assert!(!example.exists());
+    let newest_example = fs::read_dir(profile.join("examples"))
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .any(|entry| entry.file_name().to_string_lossy().starts_with("demo-"));
+    assert!(newest_example);
+    assert!(profile.join("app").exists());
+}
+
+#[test]
+fn gcs_every_member_of_a_virtual_workspace() {
+    let dir = virtual_workspace();
+    let deps = dir.path().join("target/debug/deps");
+    let stale = [
+        "libfirst-0123456789abcdef.rlib",
+        "libsecond-0123456789abcdef.rlib",
+    ]
+    .map(|name| deps.join(name));
+    for path in &stale {
+        fs::write(path, "").unwrap();
+    }
+    let in_use = |name: &str| {
+        fs::read_dir(&deps)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .any(|file| file.starts_with(&format!("lib{name}-")) && file.ends_with(".rlib"))
+    };
+
+    gc(dir.path(), &[]);
+    assert!(stale.iter().all(|path| !path.exists()));
+    assert!(in_use("first"));
+    assert!(in_use("second"));
+}
+
+/// Triple of the host rustc builds for.
+fn host_triple() -> String {
+    let output = Command::new("rustc").arg("-vV").output().unwrap();
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn gcs_the_build_target_of_the_config() {
+    let dir = project();
+    let triple = host_triple();
+    fs::create_dir(dir.path().join(".cargo")).unwrap();
+    fs::write(
+        dir.path().join(".cargo/config.toml"),
+        format!("[build]\ntarget = \"{triple}\"\n"),
+    )
+    .unwrap();
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--offline", "--quiet"])
+        .current_dir(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let stale = dir
+        .path()
+        .join("target")
+        .join(&triple)
+        .join("debug/deps/libstale-0123456789abcdef.rlib");
+    fs::write(&stale, "").unwrap();
+    let host_stale = dir
+        .path()
+        .join("target/debug/deps/libstale-0123456789abcdef.rlib");
+    fs::write(&host_stale, "").unwrap();
+
+    gc(dir.path(), &[]);
+    assert!(!stale.exists());
+    // Only looked into with `--include-host`
+    assert!(host_stale.exists());
+}
+
+#[test]
+fn include_host_gcs_the_target_and_the_host() {
+    let dir = project();
+    let triple = host_triple();
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--offline", "--quiet", "--target", &triple])
+        .current_dir(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let target_profile = dir.path().join("target").join(&triple).join("debug");
+    let host_profile = dir.path().join("target/debug");
+    let stale = [&target_profile, &host_profile]
+        .map(|profile| profile.join("deps/libstale-0123456789abcdef.rlib"));
+    for path in &stale {
+        fs::write(path, "").unwrap();
+    }
+    let in_use = |profile: &Path| {
+        fs::read_dir(profile.join("deps"))
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.file_name().to_string_lossy().starts_with("libapp-"))
+    };
+
+    gc(dir.path(), &["--include-host", "--", "--target", &triple]);
+    assert!(stale.iter().all(|path| !path.exists()));
+    assert!(in_use(&target_profile));
+    assert!(in_use(&host_profile));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn skip_in_use_keeps_files_other_processes_have_open() {
+    let dir = project();
+    let deps = dir.path().join("target/debug/deps");
+    let open = deps.join("libstale-0123456789abcdef.rlib");
+    let closed = deps.join("libstale-fedcba9876543210.rlib");
+    fs::write(&open, "").unwrap();
+    fs::write(&closed, "").unwrap();
+    let mut child = Command::new("sleep")
+        .arg("60")
+        .stdin(fs::File::open(&open).unwrap())
+        .spawn()
+        .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+        .args(["gc", "--offline", "--skip-in-use"])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert!(open.exists());
+    assert!(!closed.exists());
+}
+
+#[test]
+fn summary_counts_only_the_phases_that_ran() {
+    let dir = project();
+
+    let (stdout, _) = gc(dir.path(), &["--deps-only"]);
+    assert!(stdout.contains("Removed 0 files from"), "{stdout}");
+    assert!(!stdout.contains("incremental"), "{stdout}");
+
+    let (stdout, _) = gc(dir.path(), &["--incremental-only"]);
+    assert!(
+        stdout.contains("Removed 0 incremental directories from"),
+        "{stdout}"
+    );
+    assert!(!stdout.contains("files"), "{stdout}");
+}
+
+#[cfg(unix)]
+#[test]
+fn counts_hardlinked_files_once_in_the_reclaimed_size() {
+    let dir = project();
+    let deps = dir.path().join("target/debug/deps");
+    let older = deps.join("libstale-0123456789abcdef.rlib");
+    fs::write(&older, "x".repeat(1000)).unwrap();
+    fs::hard_link(&older, deps.join("libstale-1111111111111111.rlib")).unwrap();
+
+    let (stdout, _) = gc(
+        dir.path(),
+        &["--format", "json", "--apparent-size", "--deps-only"],
+    );
+    let summary: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(summary[0]["removed_files"], 2);
+    assert_eq!(summary[0]["reclaimed_bytes"], 1000);
+}
+
+#[test]
+fn verbose_lists_reclaimed_size_of_each_crate() {
+    let dir = project();
+    let deps = dir.path().join("target/debug/deps");
+    // Files of the lib of `big` and the `.d` of `small` share their crate
+    fs::write(deps.join("libbig-0123456789abcdef.rlib"), "x".repeat(2000)).unwrap();
+    fs::write(deps.join("libbig-0123456789abcdef.rmeta"), "x".repeat(1000)).unwrap();
+    fs::write(deps.join("libsmall-0123456789abcdef.rlib"), "x".repeat(500)).unwrap();
+    fs::write(deps.join("small-0123456789abcdef.d"), "x".repeat(500)).unwrap();
+
+    let (stdout, _) = gc(dir.path(), &["--verbose", "--apparent-size", "--deps-only"]);
+    let crates = stdout
+        .lines()
+        .skip_while(|line| *line != "largest crates by reclaimed size:")
+        .skip(1)
+        .take_while(|line| line.starts_with("  "))
+        .collect::<Vec<_>>();
+    assert_eq!(crates, ["  big: 3 kB", "  small: 1 kB"]);
+}
+
+#[test]
+fn removes_debug_info_of_removed_binaries_only() {
+    let dir = project();
+    fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--offline", "--quiet"])
+        .current_dir(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let deps = dir.path().join("target/debug/deps");
+    let fresh = fs::read_dir(&deps)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            name.starts_with("app-") && path.extension().is_none_or(|ext| ext == "exe")
+        })
+        .unwrap();
+    let mut fresh_bundle = fresh.into_os_string();
+    fresh_bundle.push(".dSYM");
+    let fresh_bundle = Path::new(&fresh_bundle).join("Contents/Resources/DWARF/app");
+    fs::create_dir_all(fresh_bundle.parent().unwrap()).unwrap();
+    fs::write(&fresh_bundle, "").unwrap();
+    // A stale binary with the debug info of each platform: a `.dSYM` bundle on
+    // macOS, a `.pdb` on Windows and split `.dwp` and `.o` files on Linux
+    fs::write(deps.join("stale-0123456789abcdef"), "").unwrap();
+    let bundle = deps.join("stale-0123456789abcdef.dSYM");
+    fs::create_dir_all(bundle.join("Contents/Resources/DWARF")).unwrap();
+    fs::write(bundle.join("Contents/Resources/DWARF/stale"), "").unwrap();
+    let split = ["pdb", "dwp", "o"].map(|ext| deps.join(format!("stale-0123456789abcdef.{ext}")));
+    for file in &split {
+        fs::write(file, "").unwrap();
+    }
+
+    gc(dir.path(), &["--deps-only"]);
+    assert!(!deps.join("stale-0123456789abcdef").exists());
+    assert!(!bundle.exists());
+    assert!(split.iter().all(|file| !file.exists()));
+    assert!(fresh_bundle.exists());
+}
+
+#[test]
+fn color_never_and_no_color_print_plain_output() {
+    let dir = project();
+    let deps = dir.path().join("target/debug/deps");
+    // Colored even when not printing to a terminal, unless told otherwise
+    let colored = |args: &[&str], envs: &[(&str, &str)]| {
+        fs::write(deps.join("libstale-0123456789abcdef.rlib"), "x").unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+            .args(["gc", "--offline"])
+            .args(args)
+            .env("CLICOLOR_FORCE", "1")
+            .envs(envs.iter().copied())
+            .current_dir(dir.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout).unwrap().contains('\x1b')
+    };
+
+    assert!(colored(&[], &[]));
+    assert!(colored(&["--color", "always"], &[("NO_COLOR", "1")]));
+    assert!(!colored(&["--color", "never"], &[]));
+    assert!(!colored(&[], &[("NO_COLOR", "1")]));
+}
+
+#[test]
+fn gcs_incremental_of_a_profile_without_deps() {
+    let dir = project();
+    let profile = dir.path().join("target/debug");
+    fs::remove_dir_all(profile.join("deps")).unwrap();
+    let unit = profile.join("incremental/stale-0w2sozlfdr77a");
+    let older = unit.join("s-older-1onhqm9-03us");
+    fs::create_dir_all(&older).unwrap();
+    filetime::set_file_mtime(&older, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+    let newer = unit.join("s-newer-1juwv4w-61hz");
+    fs::create_dir_all(&newer).unwrap();
+    fs::write(newer.join("dep-graph.bin"), "").unwrap();
+
+    gc(dir.path(), &["--newest-only"]);
+    assert!(!older.exists());
+    assert!(newer.exists());
+
+    // Never built in release
+    let (stdout, _) = gc(dir.path(), &["--newest-only", "--release"]);
+    assert!(stdout.contains("no build artifacts found"));
+}
+
+#[test]
+fn draws_no_progress_when_not_on_a_terminal() {
+    let dir = project();
+    fs::write(dir.path().join("src/lib.rs"), "pub fn changed() {}").unwrap();
+
+    for args in [&[][..], &["--no-progress"]] {
+        let (stdout, stderr) = gc(dir.path(), args);
+        for output in [stdout, stderr] {
+            assert!(!output.contains(['\x1b', '\r']), "{output:?}");
+        }
+    }
+}
+
+#[test]
+fn partial_removal_failure_exits_with_its_code() {
+    let dir = project();
+    let profile = dir.path().join("target/debug");
+    let epoch = filetime::FileTime::from_unix_time(0, 0);
+    let stale = profile.join("deps/libstale-0123456789abcdef.rlib");
+    fs::write(&stale, "").unwrap();
+    filetime::set_file_mtime(&stale, epoch).unwrap();
+    fs::write(profile.join("deps/libstale-fedcba9876543210.rlib"), "").unwrap();
+    let unit = profile.join("incremental/stale-0w2sozlfdr77a");
+    let session = unit.join("s-older-1onhqm9-03us");
+    fs::create_dir_all(&session).unwrap();
+    filetime::set_file_mtime(&session, epoch).unwrap();
+    fs::create_dir_all(unit.join("s-newer-1juwv4w-61hz")).unwrap();
+    let plan = tempfile::tempdir().unwrap();
+    let plan = plan.path().join("plan.json");
+    gc(
+        dir.path(),
+        &["--newest-only", "--output-plan", plan.to_str().unwrap()],
+    );
+    // A directory that can't be removed even as root, it's a file now
+    fs::remove_dir(&session).unwrap();
+    fs::write(&session, "").unwrap();
+    filetime::set_file_mtime(&session, epoch).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+        .args(["gc", "--offline", "--apply-plan", plan.to_str().unwrap()])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3), "{output:?}");
+    assert!(!stale.exists());
+    assert!(session.exists());
+}
+
+#[test]
+fn quick_estimates_without_building() {
+    let dir = project();
+    let deps = dir.path().join("target/debug/deps");
+    let older = deps.join("libstale-0123456789abcdef.rlib");
+    fs::write(&older, "").unwrap();
+    filetime::set_file_mtime(&older, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+    fs::write(deps.join("libstale-fedcba9876543210.rlib"), "").unwrap();
+    // A build would fail, and rebuilding would change the deps directory
+    fs::write(dir.path().join("src/lib.rs"), "fn broken(").unwrap();
+    let modified = fs::metadata(&deps).unwrap().modified().unwrap();
+
+    let (_, stderr) = gc(dir.path(), &["--dry-run", "--quick"]);
+    assert!(stderr.contains("found 1 outdated files"), "{stderr}");
+    assert!(stderr.contains("estimated by modification time"));
+    assert!(older.exists());
+    assert_eq!(fs::metadata(&deps).unwrap().modified().unwrap(), modified);
+}
+
+#[test]
+fn dry_run_counts_fingerprints_of_outdated_files() {
+    let dir = project();
+    let profile = dir.path().join("target/debug");
+    fs::write(
+        profile.join("deps/libstale-0123456789abcdef.rlib"),
+        "x".repeat(10),
+    )
+    .unwrap();
+    let fingerprint = profile.join(".fingerprint/stale-0123456789abcdef");
+    fs::create_dir(&fingerprint).unwrap();
+    fs::write(fingerprint.join("lib-stale"), "x".repeat(100)).unwrap();
+
+    let (stdout, _) = gc(
+        dir.path(),
+        &["--dry-run", "--format", "json", "--apparent-size"],
+    );
+    let dry_run: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(dry_run[0]["reclaimable_bytes"], 110);
+    let fingerprints = dry_run[0]["categories"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|category| category["name"] == ".fingerprint directories")
+        .unwrap();
+    assert_eq!(fingerprints["count"], 1);
+    assert_eq!(fingerprints["bytes"], 100);
+    assert!(fingerprint.exists());
+
+    gc(dir.path(), &[]);
+    assert!(!fingerprint.exists());
+}
+
+#[test]
+fn measured_delta_matches_the_removed_sizes() {
+    let dir = project();
+    let deps = dir.path().join("target/debug/deps");
+    fs::write(
+        deps.join("libstale-0123456789abcdef.rlib"),
+        "x".repeat(1000),
+    )
+    .unwrap();
+    fs::write(deps.join("stale-0123456789abcdef"), "x".repeat(1500)).unwrap();
+
+    let (stdout, stderr) = gc(
+        dir.path(),
+        &["--measure-delta", "--apparent-size", "--deps-only"],
+    );
+    assert!(
+        stdout.contains("Measured 2.50 kB reclaimed in") && stdout.contains("2.50 kB estimated"),
+        "{stdout}"
+    );
+    assert!(!stderr.contains("differs from the estimate"));
+}
+
+#[test]
+fn fails_cleanly_outside_a_cargo_project() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+        .args(["gc", "--offline"])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Error: not inside a Cargo project (no Cargo.toml found)\n"
+    );
+
+    // Commands that need no project still run
+    gc(dir.path(), &["completions", "bash"]);
+}
+
+#[test]
+fn dry_run_summarizes_each_category() {
+    let dir = project();
+    let profile = dir.path().join("target/debug");
+    fs::write(
+        profile.join("deps/libstale-0123456789abcdef.rlib"),
+        "x".repeat(10),
+    )
+    .unwrap();
+    fs::write(profile.join("deps/stale-0123456789abcdef.d"), "x".repeat(5)).unwrap();
+    fs::create_dir(profile.join(".fingerprint/stale-0123456789abcdef")).unwrap();
+    fs::create_dir(profile.join("build/gone-1111111111111111")).unwrap();
+    let unit = profile.join("incremental/stale-0w2sozlfdr77a");
+    let older = unit.join("s-older-1onhqm9-03us");
+    fs::create_dir_all(&older).unwrap();
+    filetime::set_file_mtime(&older, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+    fs::create_dir_all(unit.join("s-newer-1juwv4w-61hz")).unwrap();
+
+    let (stdout, stderr) = gc(dir.path(), &["--dry-run", "--apparent-size"]);
+    let lines = stdout
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        [
+            "deps artifacts 1 10 B",
+            ".d files 1 5 B",
+            "build script directories 1 0 B",
+            ".fingerprint directories 1 0 B",
+            "incremental directories 1 0 B",
+            "total 5 15 B",
+        ]
+    );
+    assert!(!stderr.contains("s-older-1onhqm9-03us"));
+
+    // Paths are only listed under --verbose
+    let (_, stderr) = gc(dir.path(), &["--dry-run", "--verbose"]);
+    assert!(stderr.contains("s-older-1onhqm9-03us"));
+    assert!(older.exists());
+}
+
+#[test]
+fn output_writes_the_printed_summary() {
+    let dir = project();
+    let output = dir.path().join("reports/gc/summary");
+    let output_arg = output.to_str().unwrap();
+
+    let (stdout, _) = gc(dir.path(), &["--format", "json", "--output", output_arg]);
+    let written: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+    assert_eq!(
+        written,
+        serde_json::from_str::<serde_json::Value>(&stdout).unwrap()
+    );
+
+    let (stdout, _) = gc(dir.path(), &["--output", output_arg]);
+    let summary = stdout
+        .lines()
+        .find(|line| line.starts_with("Removed"))
+        .unwrap();
+    assert_eq!(fs::read_to_string(&output).unwrap(), format!("{summary}\n"));
+
+    // Quiet on the console only
+    let (stdout, _) = gc(dir.path(), &["--quiet", "--output", output_arg]);
+    assert_eq!(stdout, "");
+    assert_eq!(fs::read_to_string(&output).unwrap(), format!("{summary}\n"));
+}
+
+#[test]
+fn all_dry_run_lists_every_category_without_removing() {
+    let dir = project();
+    let stale = dir
+        .path()
+        .join("target/debug/deps/libstale-0123456789abcdef.rlib");
+    fs::write(&stale, "x".repeat(1000)).unwrap();
+    let cargo_home = tempfile::tempdir().unwrap();
+    let crate_file = cargo_home
+        .path()
+        .join("registry/cache/index.crates.io-6f17d22bba15001f/unused-0.1.0.crate");
+    fs::create_dir_all(crate_file.parent().unwrap()).unwrap();
+    fs::write(&crate_file, "x".repeat(2000)).unwrap();
+    let checkout = cargo_home
+        .path()
+        .join("git/checkouts/repo-0123456789abcdef/def5678");
+    fs::create_dir_all(&checkout).unwrap();
+    fs::write(checkout.join("lib.rs"), "x".repeat(3000)).unwrap();
+    let all_gc = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+            .args(["gc", "--offline", "--all", "--apparent-size"])
+            .args(args)
+            .env("CARGO_HOME", cargo_home.path())
+            .current_dir(dir.path())
+            .output()
+            .unwrap()
+    };
+
+    let output = all_gc(&["--dry-run"]);
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines = stdout
+        .lines()
+        .skip_while(|line| *line != "Reclaimable in total:")
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        [
+            "Reclaimable in total:",
+            "target directory 1 kB",
+            "registry caches 2 kB",
+            "git checkouts 3 kB",
+            "total 6 kB",
+        ]
+    );
+    assert!(stale.exists() && crate_file.exists() && checkout.exists());
+
+    // Too broad to run unconfirmed
+    let output = all_gc(&[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stale.exists() && crate_file.exists() && checkout.exists());
+}
+
+#[test]
+fn package_only_gcs_crates_of_the_selected_packages() {
+    let dir = virtual_workspace();
+    let deps = dir.path().join("target/debug/deps");
+    let first = deps.join("libfirst-0123456789abcdef.rlib");
+    let second = deps.join("libsecond-0123456789abcdef.rlib");
+    fs::write(&first, "").unwrap();
+    fs::write(&second, "").unwrap();
+
+    gc(dir.path(), &["--", "-p", "first"]);
+    assert!(!first.exists());
+    assert!(second.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn keeps_outdated_files_hardlinked_to_kept_files() {
+    let dir = project();
+    let profile = dir.path().join("target/debug");
+    let linked = profile.join("deps/tool-0123456789abcdef");
+    fs::write(&linked, "x".repeat(1000)).unwrap();
+    fs::hard_link(&linked, profile.join("tool")).unwrap();
+    let stale = profile.join("deps/libstale-fedcba9876543210.rlib");
+    fs::write(&stale, "x".repeat(100)).unwrap();
+
+    let (stdout, _) = gc(
+        dir.path(),
+        &["--format", "json", "--apparent-size", "--deps-only"],
+    );
+    let summary: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(summary[0]["removed_files"], 1);
+    assert_eq!(summary[0]["reclaimed_bytes"], 100);
+    assert!(linked.exists());
+    assert!(!stale.exists());
+}
+
+#[test]
+fn protect_keeps_paths_matching_the_glob() {
+    let dir = project();
+    let profile = dir.path().join("target/debug");
+    let plugin = profile.join("deps/libmy_plugin-0123456789abcdef.rlib");
+    let plugin_dep_info = profile.join("deps/my_plugin-0123456789abcdef.d");
+    let other = profile.join("deps/libother-fedcba9876543210.rlib");
+    for file in [&plugin, &plugin_dep_info, &other] {
+        fs::write(file, "").unwrap();
+    }
+    let epoch = filetime::FileTime::from_unix_time(0, 0);
+    let mut sessions = vec![];
+    for unit in ["my_plugin-0w2sozlfdr77a", "other-10xcqcvhffb7x"] {
+        let older = profile
+            .join("incremental")
+            .join(unit)
+            .join("s-older-1onhqm9-03us");
+        fs::create_dir_all(&older).unwrap();
+        fs::write(older.join("dep-graph.bin"), "").unwrap();
+        filetime::set_file_mtime(&older, epoch).unwrap();
+        let newer = profile
+            .join("incremental")
+            .join(unit)
+            .join("s-newer-1juwv4w-61hz");
+        fs::create_dir_all(&newer).unwrap();
+        fs::write(newer.join("dep-graph.bin"), "").unwrap();
+        sessions.push(older);
+    }
+
+    gc(
+        dir.path(),
+        &[
+            "--protect",
+            "deps/*my_plugin*.rlib",
+            "--protect",
+            "incremental/my_plugin-*",
+        ],
+    );
+    // With the other files of the build
+    assert!(plugin.exists() && plugin_dep_info.exists());
+    assert!(sessions[0].exists());
+    assert!(!other.exists());
+    assert!(!sessions[1].exists());
+}
+
+#[test]
+fn dry_run_prints_a_json_plan_of_each_entry() {
+    let dir = project();
+    let profile = dir.path().join("target/debug");
+    let rlib = profile.join("deps/libstale-0123456789abcdef.rlib");
+    fs::write(&rlib, "x".repeat(10)).unwrap();
+    let dep_info = profile.join("deps/stale-0123456789abcdef.d");
+    fs::write(&dep_info, "x".repeat(20)).unwrap();
+
+    let (stdout, _) = gc(
+        dir.path(),
+        &[
+            "--dry-run",
+            "--format",
+            "json",
+            "--apparent-size",
+            "--deps-only",
+        ],
+    );
+    let dry_run: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    let dry_run = dry_run.as_array().unwrap();
+    assert_eq!(dry_run.len(), 1);
+    assert_eq!(dry_run[0]["reclaimable_bytes"], 30);
+    let categories = dry_run[0]["categories"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|category| {
+            (
+                category["name"].as_str().unwrap(),
+                category["count"].as_u64().unwrap(),
+                category["bytes"].as_u64().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        categories,
+        [
+            ("deps artifacts", 1, 10),
+            (".d files", 1, 20),
+            ("build script directories", 0, 0),
+            (".fingerprint directories", 0, 0),
+            ("incremental directories", 0, 0),
+        ]
+    );
+    let mut entries = dry_run[0]["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            (
+                Path::new(entry["path"].as_str().unwrap())
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap(),
+                entry["category"].as_str().unwrap(),
+                entry["bytes"].as_u64().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+    assert_eq!(
+        entries,
+        [
+            ("libstale-0123456789abcdef.rlib", "deps artifacts", 10),
+            ("stale-0123456789abcdef.d", ".d files", 20),
+        ]
+    );
+    assert!(rlib.exists() && dep_info.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn gcs_the_real_directory_of_a_symlinked_target_directory() {
+    let dir = project();
+    let cache = tempfile::tempdir().unwrap();
+    let real_target = cache.path().join("target");
+    fs::rename(dir.path().join("target"), &real_target).unwrap();
+    std::os::unix::fs::symlink(&real_target, dir.path().join("target")).unwrap();
+    let stale = real_target.join("debug/deps/libstale-0123456789abcdef.rlib");
+    fs::write(&stale, "").unwrap();
+
+    gc(dir.path(), &["--deps-only"]);
+    assert!(!stale.exists());
+    assert!(fs::symlink_metadata(dir.path().join("target"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert!(real_target.join("debug/deps").is_dir());
+}
+
+#[test]
+fn size_sort_lists_the_largest_entries_first() {
+    let dir = project();
+    let deps = dir.path().join("target/debug/deps");
+    for (name, size) in [
+        ("libmedium-1111111111111111.rlib", 2_000),
+        ("libsmall-2222222222222222.rlib", 1_000),
+        ("liblarge-3333333333333333.rlib", 3_000),
+    ] {
+        fs::write(deps.join(name), "x".repeat(size)).unwrap();
+    }
+
+    let (_, stderr) = gc(
+        dir.path(),
+        &[
+            "--verbose",
+            "--size-sort",
+            "--dry-run",
+            "--apparent-size",
+            "--deps-only",
+        ],
+    );
+    let listed = stderr
+        .lines()
+        .skip_while(|line| *line != "files to remove:")
+        .skip(1)
+        .take(3)
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    assert_eq!(listed.len(), 3, "{stderr}");
+    for (line, (size, name)) in listed.iter().zip([
+        ("3 kB", "liblarge-3333333333333333.rlib"),
+        ("2 kB", "libmedium-1111111111111111.rlib"),
+        ("1 kB", "libsmall-2222222222222222.rlib"),
+    ]) {
+        assert!(
+            line.starts_with(size) && line.ends_with(&format!("{name}\"")),
+            "{stderr}"
+        );
+    }
+}
+
+#[test]
+fn gcs_the_directory_of_a_custom_profile() {
+    let dir = project();
+    let mut manifest = fs::read_to_string(dir.path().join("Cargo.toml")).unwrap();
+    manifest.push_str("\n[profile.ci]\ninherits = \"test\"\n");
+    fs::write(dir.path().join("Cargo.toml"), manifest).unwrap();
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--offline", "--quiet", "--profile", "ci"])
+        .current_dir(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let stale = dir
+        .path()
+        .join("target/ci/deps/libstale-0123456789abcdef.rlib");
+    fs::write(&stale, "").unwrap();
+    let debug_stale = dir
+        .path()
+        .join("target/debug/deps/libstale-0123456789abcdef.rlib");
+    fs::write(&debug_stale, "").unwrap();
+
+    gc(dir.path(), &["--profile", "ci"]);
+    assert!(!stale.exists());
+    assert!(debug_stale.exists());
+}
+
+#[test]
+fn gcs_the_project_of_the_manifest_path() {
+    let dir = project();
+    let stale = dir
+        .path()
+        .join("target/debug/deps/libstale-0123456789abcdef.rlib");
+    fs::write(&stale, "").unwrap();
+    let elsewhere = tempfile::tempdir().unwrap();
+    let manifest_path = dir.path().join("Cargo.toml");
+
+    gc(
+        elsewhere.path(),
+        &["--manifest-path", manifest_path.to_str().unwrap()],
+    );
+    assert!(!stale.exists());
+}
+
+#[test]
+fn gcs_the_shared_target_directory_from_a_member() {
+    let dir = virtual_workspace();
+    let stale = dir
+        .path()
+        .join("target/debug/deps/libsecond-0123456789abcdef.rlib");
+    fs::write(&stale, "").unwrap();
+
+    gc(&dir.path().join("first"), &[]);
+    assert!(!stale.exists());
+    assert!(!dir.path().join("first/target").exists());
+}
+
+#[test]
+fn check_fails_over_max_size_without_removing() {
+    let dir = project();
+    let stale = dir
+        .path()
+        .join("target/debug/deps/libstale-0123456789abcdef.rlib");
+    fs::write(&stale, "x".repeat(10_000)).unwrap();
+    let check = |max_size: &str| {
+        Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+            .args(["gc", "--offline", "--check", "--max-size", max_size])
+            .current_dir(dir.path())
+            .output()
+            .unwrap()
+            .status
+            .code()
+    };
+
+    assert_eq!(check("1GB"), Some(0));
+    assert_eq!(check("1KB"), Some(5));
+    assert!(stale.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn builds_with_the_rustc_wrapper_of_the_environment() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = project();
+    let log = dir.path().join("wrapper.log");
+    let wrapper = dir.path().join("wrapper.sh");
+    fs::write(
+        &wrapper,
+        format!("#!/bin/sh\necho \"$@\" >> {:?}\nexec \"$@\"\n", log),
+    )
+    .unwrap();
+    fs::set_permissions(&wrapper, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::write(dir.path().join("src/lib.rs"), "pub fn changed() {}").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+        .args(["gc", "--offline"])
+        .env("RUSTC_WRAPPER", &wrapper)
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert!(fs::read_to_string(&log)
+        .unwrap()
+        .contains("--crate-name app"));
+}
+
+#[test]
+fn locked_fails_with_cargos_error_when_the_lockfile_is_stale() {
+    let dir = project();
+    fs::create_dir_all(dir.path().join("dep/src")).unwrap();
+    fs::write(
+        dir.path().join("dep/Cargo.toml"),
+        "[package]\nname = \"dep\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+    )
+    .unwrap();
+    fs::write(dir.path().join("dep/src/lib.rs"), "").unwrap();
+    let mut manifest = fs::read_to_string(dir.path().join("Cargo.toml")).unwrap();
+    manifest.push_str("\n[dependencies]\ndep = { path = \"dep\" }\n");
+    fs::write(dir.path().join("Cargo.toml"), manifest).unwrap();
+    let lockfile = fs::read_to_string(dir.path().join("Cargo.lock")).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-gc"))
+        .args(["gc", "--offline", "--locked"])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    // Cargo refuses to build, as it can't update the lockfile
+    assert_eq!(output.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--locked"));
+    assert_eq!(
+        fs::read_to_string(dir.path().join("Cargo.lock")).unwrap(),
+        lockfile
+    );
+}